@@ -1,3 +1,4 @@
+use crate::compiler::diagnostic::Span;
 use std::fmt;
 
 /// Represents the binary operators supported by the language.
@@ -7,6 +8,32 @@ pub enum Op {
     Subtract,
     Multiply,
     Divide,
+    /// Matrix multiplication: `A @ B`.
+    MatMul,
+    /// Element-wise (Hadamard) multiplication: `A .* B`.
+    ElemMul,
+    /// Element-wise division: `A ./ B`.
+    ElemDiv,
+    /// Exponentiation: `a ^ b`, or repeated matrix multiplication for `A ^ n`.
+    Power,
+    /// Element-wise exponentiation: `A .^ B`.
+    ElemPower,
+    /// Equality comparison: `a == b`.
+    Equal,
+    /// Inequality comparison: `a != b`.
+    NotEqual,
+    /// `a < b`.
+    Less,
+    /// `a <= b`.
+    LessEqual,
+    /// `a > b`.
+    Greater,
+    /// `a >= b`.
+    GreaterEqual,
+    /// Short-circuiting logical and: `a && b`.
+    And,
+    /// Short-circuiting logical or: `a || b`.
+    Or,
 }
 
 impl fmt::Display for Op {
@@ -16,29 +43,114 @@ impl fmt::Display for Op {
             Op::Subtract => write!(f, "-"),
             Op::Multiply => write!(f, "*"),
             Op::Divide => write!(f, "/"),
+            Op::MatMul => write!(f, "@"),
+            Op::ElemMul => write!(f, ".*"),
+            Op::ElemDiv => write!(f, "./"),
+            Op::Power => write!(f, "^"),
+            Op::ElemPower => write!(f, ".^"),
+            Op::Equal => write!(f, "=="),
+            Op::NotEqual => write!(f, "!="),
+            Op::Less => write!(f, "<"),
+            Op::LessEqual => write!(f, "<="),
+            Op::Greater => write!(f, ">"),
+            Op::GreaterEqual => write!(f, ">="),
+            Op::And => write!(f, "&&"),
+            Op::Or => write!(f, "||"),
         }
     }
 }
 
-/// Represents an expression in the AST.
+impl Op {
+    /// Returns true for the comparison operators, which produce a boolean.
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            Op::Equal | Op::NotEqual | Op::Less | Op::LessEqual | Op::Greater | Op::GreaterEqual
+        )
+    }
+}
+
+/// Represents the unary prefix operators supported by the language.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnaryOp {
+    /// Logical negation: `!a`.
+    Not,
+    /// Arithmetic negation: `-x`, element-wise for matrices.
+    Neg,
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnaryOp::Not => write!(f, "!"),
+            UnaryOp::Neg => write!(f, "-"),
+        }
+    }
+}
+
+/// Represents an expression in the AST, with its location in the source.
 #[derive(Debug, Clone, PartialEq)]
-pub enum Expr {
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    /// Creates an expression node covering `span`.
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+/// The different kinds of expressions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
     /// A floating point number.
     Number(f64),
+    /// An integer literal. Until there is an integer type it is used as a float.
+    Integer(i64),
+    /// A boolean literal: `true` or `false`.
+    Bool(bool),
     /// A binary operation between two expressions.
     BinaryOp(Box<Expr>, Op, Box<Expr>),
+    /// A unary prefix operation: `!a`.
+    UnaryOp(UnaryOp, Box<Expr>),
+    /// A conditional expression: `if c { a } else { b }`.
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
     /// A matrix literal.
     MatrixLiteral(Vec<Vec<Expr>>),
     /// A variable identifier.
     Identifier(String),
+    /// The transpose of an expression: `A'` or `transpose(A)`.
+    Transpose(Box<Expr>),
+    /// Element access: `A[i, j]`, or `v[i]` for linear (row-major) indexing.
+    Index(Box<Expr>, Vec<Expr>),
+    /// Sub-matrix extraction: `A[r0:r1, c0:c1]`, or `v[i0:i1]` for linear slicing.
+    Slice(Box<Expr>, Vec<Subscript>),
+    /// A call to another function in the program: `f(a, b)`.
+    Call(String, Vec<Expr>),
 }
 
-impl fmt::Display for Expr {
+impl fmt::Display for ExprKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Expr::Number(n) => write!(f, "{}", n),
-            Expr::BinaryOp(left, op, right) => write!(f, "({} {} {})", left, op, right),
-            Expr::MatrixLiteral(rows) => {
+            // Spelled like the `nan` constant so the output parses back.
+            ExprKind::Number(n) if n.is_nan() => write!(f, "nan"),
+            ExprKind::Number(n) => write!(f, "{}", n),
+            ExprKind::Integer(n) => write!(f, "{}", n),
+            ExprKind::Bool(b) => write!(f, "{}", b),
+            ExprKind::BinaryOp(left, op, right) => write!(f, "({} {} {})", left, op, right),
+            ExprKind::UnaryOp(op, expr) => write!(f, "{}{}", op, expr),
+            ExprKind::If(cond, then_expr, else_expr) => {
+                write!(f, "if {} {{ {} }} else {{ {} }}", cond, then_expr, else_expr)
+            }
+            ExprKind::MatrixLiteral(rows) => {
                 write!(f, "[")?;
                 for (i, row) in rows.iter().enumerate() {
                     if i > 0 {
@@ -55,25 +167,218 @@ impl fmt::Display for Expr {
                 }
                 write!(f, "]")
             }
-            Expr::Identifier(name) => write!(f, "{}", name),
+            ExprKind::Identifier(name) => write!(f, "{}", name),
+            ExprKind::Transpose(expr) => write!(f, "{}'", expr),
+            ExprKind::Index(target, indices) => {
+                write!(f, "{}[", target)?;
+                for (i, index) in indices.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", index)?;
+                }
+                write!(f, "]")
+            }
+            ExprKind::Call(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            ExprKind::Slice(target, subscripts) => {
+                write!(f, "{}[", target)?;
+                for (i, subscript) in subscripts.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", subscript)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// Represents one dimension of a slice expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Subscript {
+    /// A single position: `i`. Keeps the dimension, with length 1.
+    Point(Expr),
+    /// A range `start:end:step`. Missing bounds cover the whole dimension and the step defaults to 1.
+    Range {
+        start: Option<Expr>,
+        end: Option<Expr>,
+        step: Option<Expr>,
+    },
+}
+
+impl fmt::Display for Subscript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subscript::Point(expr) => write!(f, "{}", expr),
+            Subscript::Range { start, end, step } => {
+                if let Some(start) = start {
+                    write!(f, "{}", start)?;
+                }
+                write!(f, ":")?;
+                if let Some(end) = end {
+                    write!(f, "{}", end)?;
+                }
+                if let Some(step) = step {
+                    write!(f, ":{}", step)?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
-/// Represents a statement in the AST.
+/// Represents a statement in the AST, with its location in the source.
 #[derive(Debug, Clone, PartialEq)]
-pub enum Stmt {
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+impl Stmt {
+    /// Creates a statement node covering `span`.
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+/// The different kinds of statements.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
     /// A variable binding: `let x = ...`
     Let(String, Expr),
+    /// An assignment to an existing variable: `x = ...`, or `x += ...` with the compound operator.
+    Assign(String, Option<Op>, Expr),
+    /// A store into one matrix element: `A[i, j] = ...`, or `A[i, j] += ...` with the compound operator.
+    IndexAssign(String, Vec<Expr>, Option<Op>, Expr),
     /// A return statement: `return ...`
     Return(Expr),
+    /// An expression evaluated for its side effects: `f(x);`
+    Expr(Expr),
+    /// A conditional: `if c { ... } else { ... }`. An `else if` chain nests
+    /// another `If` as the only statement of the else block.
+    If(Expr, Vec<Stmt>, Option<Vec<Stmt>>),
+    /// A loop that runs while the condition holds: `while c { ... }`.
+    While(Expr, Vec<Stmt>),
+    /// A loop over the half-open range `start..end`: `for i in 0..n { ... }`.
+    For(String, Expr, Expr, Vec<Stmt>),
+    /// Exits the innermost loop: `break;`.
+    Break,
+    /// Skips to the next iteration of the innermost loop: `continue;`.
+    Continue,
 }
 
-impl fmt::Display for Stmt {
+impl fmt::Display for StmtKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StmtKind::Let(name, expr) => write!(f, "let {} = {};", name, expr),
+            StmtKind::Assign(name, op, expr) => {
+                write!(f, "{} ", name)?;
+                if let Some(op) = op {
+                    write!(f, "{}", op)?;
+                }
+                write!(f, "= {};", expr)
+            }
+            StmtKind::IndexAssign(name, indices, op, expr) => {
+                write!(f, "{}[", name)?;
+                for (i, index) in indices.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", index)?;
+                }
+                write!(f, "] ")?;
+                if let Some(op) = op {
+                    write!(f, "{}", op)?;
+                }
+                write!(f, "= {};", expr)
+            }
+            StmtKind::Return(expr) => write!(f, "return {};", expr),
+            StmtKind::Expr(expr) => write!(f, "{};", expr),
+            StmtKind::If(cond, then_body, else_body) => {
+                write!(f, "if {} {{", cond)?;
+                for stmt in then_body {
+                    write!(f, " {}", stmt)?;
+                }
+                write!(f, " }}")?;
+                if let Some(else_body) = else_body {
+                    write!(f, " else {{")?;
+                    for stmt in else_body {
+                        write!(f, " {}", stmt)?;
+                    }
+                    write!(f, " }}")?;
+                }
+                Ok(())
+            }
+            StmtKind::While(cond, body) => {
+                write!(f, "while {} {{", cond)?;
+                for stmt in body {
+                    write!(f, " {}", stmt)?;
+                }
+                write!(f, " }}")
+            }
+            StmtKind::For(var, start, end, body) => {
+                write!(f, "for {} in {}..{} {{", var, start, end)?;
+                for stmt in body {
+                    write!(f, " {}", stmt)?;
+                }
+                write!(f, " }}")
+            }
+            StmtKind::Break => write!(f, "break;"),
+            StmtKind::Continue => write!(f, "continue;"),
+        }
+    }
+}
+
+/// Represents a type annotation on a function parameter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    /// A floating point number: `f64`.
+    Scalar,
+    /// A heap-allocated matrix: `Matrix`.
+    Matrix,
+    /// A boolean: `bool`.
+    Bool,
+}
+
+impl fmt::Display for Type {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Stmt::Let(name, expr) => write!(f, "let {} = {};", name, expr),
-            Stmt::Return(expr) => write!(f, "return {};", expr),
+            Type::Scalar => write!(f, "f64"),
+            Type::Matrix => write!(f, "Matrix"),
+            Type::Bool => write!(f, "bool"),
+        }
+    }
+}
+
+/// Represents a function parameter, optionally annotated with its type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: String,
+    pub ty: Option<Type>,
+    pub span: Span,
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.ty {
+            Some(ty) => write!(f, "{}: {}", self.name, ty),
+            None => write!(f, "{}", self.name),
         }
     }
 }
@@ -82,29 +387,85 @@ impl fmt::Display for Stmt {
 #[derive(Debug, Clone, PartialEq)]
 pub struct Function {
     pub name: String,
+    pub params: Vec<Param>,
     pub body: Vec<Stmt>,
+    /// The span of the signature, `fn name(...)`.
+    pub span: Span,
 }
 
 impl fmt::Display for Function {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "fn {}() {{\n", self.name)?;
+        write!(f, "fn {}(", self.name)?;
+        for (i, param) in self.params.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", param)?;
+        }
+        writeln!(f, ") {{")?;
         for stmt in &self.body {
-            write!(f, "    {}\n", stmt)?;
+            writeln!(f, "    {}", stmt)?;
         }
         write!(f, "}}")
     }
 }
 
+/// Represents a function implemented in native code: `extern fn hypot(x: f64, y: f64) -> f64;`.
+/// Every parameter has a type, and the return type defaults to `f64`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExternFunction {
+    pub name: String,
+    pub params: Vec<Param>,
+    pub ret: Type,
+    /// The span of the whole declaration.
+    pub span: Span,
+}
+
+impl fmt::Display for ExternFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "extern fn {}(", self.name)?;
+        for (i, param) in self.params.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", param)?;
+        }
+        write!(f, ") -> {};", self.ret)
+    }
+}
+
+/// Represents a `link "libfoo.so";` directive, naming a shared library that
+/// extern functions are looked up in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    pub path: String,
+    pub span: Span,
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "link \"{}\";", self.path)
+    }
+}
+
 /// Represents the entire program.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Program {
+    pub links: Vec<Link>,
+    pub externs: Vec<ExternFunction>,
     pub functions: Vec<Function>,
 }
 
 impl fmt::Display for Program {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for link in &self.links {
+            writeln!(f, "{}", link)?;
+        }
+        for extern_fn in &self.externs {
+            writeln!(f, "{}", extern_fn)?;
+        }
         for func in &self.functions {
-            write!(f, "{}\n", func)?;
+            writeln!(f, "{}", func)?;
         }
         Ok(())
     }
@@ -1,12 +1,22 @@
-use anyhow::{anyhow, bail, Result};
+use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
-use inkwell::module::Module;
-use inkwell::types::{BasicType, BasicTypeEnum, StructType};
-use inkwell::values::{BasicValue, BasicValueEnum, PointerValue};
+use inkwell::attributes::{Attribute, AttributeLoc};
+use inkwell::intrinsics::Intrinsic;
+use inkwell::module::{Linkage, Module};
+use inkwell::types::{BasicType, BasicTypeEnum, PointerType, StructType};
+use inkwell::values::{BasicMetadataValueEnum, BasicValueEnum, FloatValue, FunctionValue, IntValue, PointerValue};
+use inkwell::{AddressSpace, FloatPredicate, IntPredicate};
 use std::collections::HashMap;
 
-use crate::compiler::ast::{Expr, Function, Op, Program, Stmt};
+use crate::compiler::ast::{Expr, ExprKind, ExternFunction, Function, Link, Op, Program, Stmt, StmtKind, Subscript, Type, UnaryOp};
+use crate::compiler::diagnostic::Span;
+use crate::compiler::error::{CompileError, Result};
+use crate::compiler::host::HostFunction;
+use crate::compiler::runtime;
+
+/// The prefix of the entry point generated for each function (see `compile_entry_point`).
+pub const ENTRY_PREFIX: &str = "__matrix_script_entry_";
 
 /// The CodeGen struct which holds the LLVM context, module, and builder.
 pub struct CodeGen<'ctx> {
@@ -14,6 +24,16 @@ pub struct CodeGen<'ctx> {
     module: Module<'ctx>,
     builder: Builder<'ctx>,
     variables: HashMap<String, (PointerValue<'ctx>, BasicTypeEnum<'ctx>)>,
+    signatures: HashMap<String, Signature>,
+    /// Functions implemented by the host that scripts can call (see `register_host_function`).
+    host_functions: Vec<HostFunction>,
+    /// The `link` directives and `extern fn` declarations of the compiled program, for the `Jit` to resolve.
+    links: Vec<Link>,
+    extern_functions: Vec<ExternFunction>,
+    /// The `(continue, break)` targets of the loops enclosing the current statement.
+    loops: Vec<(BasicBlock<'ctx>, BasicBlock<'ctx>)>,
+    /// The span of the statement or expression being compiled, where errors are reported.
+    span: Span,
     matrix_type: StructType<'ctx>,
 }
 
@@ -23,16 +43,21 @@ impl<'ctx> CodeGen<'ctx> {
         let module = context.create_module(module_name);
         let builder = context.create_builder();
 
-        let f64_type = context.f64_type();
         let i64_type = context.i64_type();
-        let f64_ptr_type = f64_type.ptr_type(inkwell::AddressSpace::default());
-        let matrix_type = context.struct_type(&[f64_ptr_type.into(), i64_type.into(), i64_type.into()], false);
+        let ptr_type = context.ptr_type(AddressSpace::default());
+        let matrix_type = context.struct_type(&[ptr_type.into(), i64_type.into(), i64_type.into()], false);
 
         Self {
             context,
             module,
             builder,
             variables: HashMap::new(),
+            signatures: HashMap::new(),
+            host_functions: Vec::new(),
+            links: Vec::new(),
+            extern_functions: Vec::new(),
+            loops: Vec::new(),
+            span: Span::default(),
             matrix_type,
         }
     }
@@ -42,30 +67,72 @@ impl<'ctx> CodeGen<'ctx> {
         &self.module
     }
 
+    /// Makes a host function callable from the programs this `CodeGen` compiles,
+    /// replacing any registered earlier under the same name. The `Jit` must map it
+    /// to its address (see `Jit::with_host_functions`).
+    pub fn register_host_function(&mut self, function: HostFunction) {
+        self.host_functions.retain(|f| f.name != function.name);
+        self.host_functions.push(function);
+    }
+
+    /// Returns the registered host functions.
+    pub fn host_functions(&self) -> &[HostFunction] {
+        &self.host_functions
+    }
+
+    /// Returns the shared libraries the compiled program links against.
+    pub fn links(&self) -> &[Link] {
+        &self.links
+    }
+
+    /// Returns the native functions the compiled program declares with `extern fn`.
+    pub fn extern_functions(&self) -> &[ExternFunction] {
+        &self.extern_functions
+    }
+
     /// Compiles a program.
     pub fn compile_program(&mut self, program: &Program) -> Result<()> {
+        self.declare_runtime_functions();
+
+        // Declare every function up front so calls can refer to functions defined later.
+        self.infer_signatures(program)?;
+        for host in &self.host_functions {
+            self.declare_external_function(&host.name, &host.params, host.ret);
+        }
+        for extern_fn in &program.externs {
+            let params: Vec<Type> = extern_fn.params.iter().filter_map(|p| p.ty).collect();
+            self.declare_external_function(&extern_fn.name, &params, extern_fn.ret);
+        }
+        self.links = program.links.clone();
+        self.extern_functions = program.externs.clone();
+
+        for function in &program.functions {
+            let signature = &self.signatures[&function.name];
+            let param_types: Vec<_> = signature.params.iter().map(|ty| self.llvm_type(*ty).into()).collect();
+            // Matrices are returned as a pointer to the matrix struct
+            let fn_type = self.llvm_type(signature.ret).fn_type(&param_types, false);
+            self.module.add_function(&function.name, fn_type, None);
+        }
+
         for function in &program.functions {
             self.compile_function(function)?;
         }
+        for function in &program.functions {
+            let fn_val = self.module.get_function(&function.name).unwrap();
+            self.compile_entry_point(fn_val)?;
+        }
         Ok(())
     }
 
     /// Compiles a function.
     fn compile_function(&mut self, function: &Function) -> Result<()> {
-        // Infer return type
-        let return_type = self.infer_return_type(function);
+        let fn_val = self.module.get_function(&function.name).unwrap();
 
-        let fn_type = match return_type {
-             FunctionReturnType::Matrix => {
-                // Return a pointer to the matrix struct
-                 self.matrix_type.ptr_type(inkwell::AddressSpace::default()).fn_type(&[], false)
-             }
-             FunctionReturnType::Scalar => {
-                 self.context.f64_type().fn_type(&[], false)
-             }
-        };
-
-        let fn_val = self.module.add_function(&function.name, fn_type, None);
+        // A function that returns values must not fall off its end, where it would have none to return.
+        let returns_value = contains_return(&function.body);
+        if returns_value && !always_returns(&function.body) {
+            return Err(CompileError::MissingReturn { name: function.name.clone(), span: function.span });
+        }
 
         // Create basic block
         let entry = self.context.append_basic_block(fn_val, "entry");
@@ -73,43 +140,314 @@ impl<'ctx> CodeGen<'ctx> {
 
         // Clear variables for new function scope
         self.variables.clear();
+        self.loops.clear();
+
+        // Parameters live in allocas like any other variable, so they can be reassigned.
+        for (param, value) in function.params.iter().zip(fn_val.get_param_iter()) {
+            value.set_name(&param.name);
+            let ty = value.get_type();
+            let alloca = self.create_entry_block_alloca(&param.name, ty);
+            self.builder.build_store(alloca, value)?;
+            self.variables.insert(param.name.clone(), (alloca, ty));
+        }
 
         for stmt in &function.body {
             self.compile_stmt(stmt)?;
         }
 
+        // Falling off the end of a function without any `return` returns zero (a scalar).
+        // Otherwise the end can only be reached from the dead code after a return.
+        if self.builder.get_insert_block().unwrap().get_terminator().is_none() {
+            if returns_value {
+                self.builder.build_unreachable()?;
+            } else {
+                let zero = fn_val.get_type().get_return_type().unwrap().const_zero();
+                self.builder.build_return(Some(&zero))?;
+            }
+        }
+
         Ok(())
     }
 
-    fn infer_return_type(&self, function: &Function) -> FunctionReturnType {
-        let mut local_types = HashMap::new();
+    /// Emits the entry point `Jit` calls `function` through: `ENTRY_PREFIX` + its name,
+    /// taking a pointer to one 8-byte slot per argument. Every entry point has the native
+    /// signature `ret(ptr)`, so the host can call functions whose parameter list it only
+    /// learns at runtime.
+    fn compile_entry_point(&mut self, function: FunctionValue<'ctx>) -> Result<()> {
+        let i64_type = self.context.i64_type();
+        let fn_type = function.get_type();
+
+        let entry_type = fn_type.get_return_type().unwrap().fn_type(&[self.ptr_type().into()], false);
+        let name = format!("{}{}", ENTRY_PREFIX, function.get_name().to_str().unwrap());
+        let entry = self.module.add_function(&name, entry_type, None);
+        self.builder.position_at_end(self.context.append_basic_block(entry, "entry"));
 
-        for stmt in &function.body {
-            match stmt {
-                Stmt::Let(name, expr) => {
-                    let ty = self.infer_expr_type(expr, &local_types);
+        let slots = entry.get_first_param().unwrap().into_pointer_value();
+        let mut args = Vec::new();
+        for (i, ty) in fn_type.get_param_types().into_iter().enumerate() {
+            let slot = unsafe { self.builder.build_gep(i64_type, slots, &[i64_type.const_int(i as u64, false)], "slot")? };
+            // A boolean fills its whole slot with 0 or 1.
+            let arg: BasicValueEnum = if ty.is_int_type() {
+                let val = self.builder.build_load(i64_type, slot, "slot_val")?.into_int_value();
+                self.builder.build_int_truncate(val, ty.into_int_type(), "arg")?.into()
+            } else {
+                self.builder.build_load(ty, slot, "arg")?
+            };
+            args.push(arg.into());
+        }
+
+        let call = self.builder.build_call(function, &args, "result")?;
+        let result = call.try_as_basic_value().left().unwrap();
+        self.builder.build_return(Some(&result))?;
+        Ok(())
+    }
+
+    /// Declares the native support routines from `runtime` that generated code calls.
+    fn declare_runtime_functions(&self) {
+        let i64_type = self.context.i64_type();
+        let raise_type = self.context.void_type().fn_type(
+            &[
+                i64_type.into(),
+                self.ptr_type().into(),
+                i64_type.into(),
+                i64_type.into(),
+                i64_type.into(),
+                i64_type.into(),
+                i64_type.into(),
+                i64_type.into(),
+            ],
+            false,
+        );
+        self.module.add_function(runtime::RAISE_FN, raise_type, None);
+
+        let pending_type = self.context.bool_type().fn_type(&[], false);
+        self.module.add_function(runtime::PENDING_FN, pending_type, None);
+    }
+
+    /// Declares a host or extern function as an external symbol, for the `Jit` to map.
+    fn declare_external_function(&self, name: &str, params: &[Type], ret: Type) {
+        let param_types: Vec<_> = params.iter().map(|ty| self.llvm_type((*ty).into()).into()).collect();
+        let fn_type = self.llvm_type(ret.into()).fn_type(&param_types, false);
+        let fn_val = self.module.add_function(name, fn_type, Some(Linkage::External));
+
+        // An `i1` only defines its low bit, but a native `bool` must be exactly 0 or 1.
+        let zeroext = self.context.create_enum_attribute(Attribute::get_named_enum_kind_id("zeroext"), 0);
+        for (i, ty) in params.iter().enumerate() {
+            if *ty == Type::Bool {
+                fn_val.add_attribute(AttributeLoc::Param(i as u32), zeroext);
+            }
+        }
+    }
+
+    /// Resolves the parameter and return types of every function in the program.
+    ///
+    /// Annotated parameters keep their annotation; an unannotated parameter takes the
+    /// type of the argument at the first call site that passes it, defaulting to a
+    /// scalar. Return types follow from the body, so this repeats until nothing changes.
+    /// Host and extern functions have fixed signatures, and no other function can share their names.
+    fn infer_signatures(&mut self, program: &Program) -> Result<()> {
+        self.signatures.clear();
+        for host in &self.host_functions {
+            let params = host.params.iter().map(|ty| ValueType::from(*ty)).collect();
+            self.signatures.insert(host.name.clone(), Signature { params, ret: host.ret.into() });
+        }
+        for extern_fn in &program.externs {
+            if self.signatures.contains_key(&extern_fn.name) {
+                return Err(CompileError::DuplicateFunction { name: extern_fn.name.clone(), span: extern_fn.span });
+            }
+            let params = extern_fn.params.iter().filter_map(|p| p.ty).map(ValueType::from).collect();
+            self.signatures.insert(extern_fn.name.clone(), Signature { params, ret: extern_fn.ret.into() });
+        }
+        for function in &program.functions {
+            if self.signatures.contains_key(&function.name) {
+                return Err(CompileError::DuplicateFunction { name: function.name.clone(), span: function.span });
+            }
+            let params = function.params.iter().map(|p| p.ty.map(ValueType::from).unwrap_or(ValueType::Scalar)).collect();
+            self.signatures.insert(function.name.clone(), Signature { params, ret: ValueType::Scalar });
+        }
+
+        // Each round settles at least one more function unless the types depend on each
+        // other in a cycle, which is reported rather than compiled with a stale guess.
+        let mut unsettled = None;
+        for _ in 0..=program.functions.len() {
+            let mut next = self.signatures.clone();
+            let mut first_call_args: HashMap<String, Vec<ValueType>> = HashMap::new();
+
+            for function in &program.functions {
+                let mut call_sites = Vec::new();
+                let returns = self.infer_return_types(function, &mut call_sites);
+                next.get_mut(&function.name).unwrap().ret = returns.first().map_or(ValueType::Scalar, |(ty, _)| *ty);
+                for (callee, arg_types) in call_sites {
+                    first_call_args.entry(callee).or_insert(arg_types);
+                }
+            }
+
+            for function in &program.functions {
+                let signature = next.get_mut(&function.name).unwrap();
+                for (i, param) in function.params.iter().enumerate() {
+                    if param.ty.is_none() {
+                        signature.params[i] = first_call_args
+                            .get(&function.name)
+                            .and_then(|args| args.get(i).copied())
+                            .unwrap_or(ValueType::Scalar);
+                    }
+                }
+            }
+
+            unsettled = program.functions.iter().find(|f| next[&f.name] != self.signatures[&f.name]);
+            if unsettled.is_none() {
+                break;
+            }
+            self.signatures = next;
+        }
+        if let Some(function) = unsettled {
+            return Err(CompileError::TypeError {
+                message: format!("Cannot infer the types of function `{}`, annotate its parameters", function.name),
+                span: function.span,
+            });
+        }
+
+        // Returns can only be told apart from types still being inferred once every signature is final.
+        for function in &program.functions {
+            let returns = self.infer_return_types(function, &mut Vec::new());
+            if let Some(&(ret, _)) = returns.first() {
+                if let Some(&(ty, span)) = returns.iter().find(|(ty, _)| *ty != ret) {
+                    return Err(CompileError::TypeError {
+                        message: format!("Function `{}` returns a {} here, but a {} elsewhere", function.name, ty.name(), ret.name()),
+                        span,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Infers the type of every `return` in a function, in source order, recording
+    /// the argument types of every call made along the way in `call_sites`. The first
+    /// return decides the function's return type, which is a scalar if there is none.
+    fn infer_return_types(&self, function: &Function, call_sites: &mut Vec<(String, Vec<ValueType>)>) -> Vec<(ValueType, Span)> {
+        let mut local_types: HashMap<String, ValueType> = function
+            .params
+            .iter()
+            .zip(&self.signatures[&function.name].params)
+            .map(|(param, ty)| (param.name.clone(), *ty))
+            .collect();
+        let mut returns = Vec::new();
+        self.infer_block_types(&function.body, &mut local_types, &mut returns, call_sites);
+        returns
+    }
+
+    /// Walks a block for `infer_return_types`. Variables declared inside nested
+    /// blocks go out of scope when the block ends.
+    fn infer_block_types(
+        &self,
+        body: &[Stmt],
+        local_types: &mut HashMap<String, ValueType>,
+        returns: &mut Vec<(ValueType, Span)>,
+        call_sites: &mut Vec<(String, Vec<ValueType>)>,
+    ) {
+        for stmt in body {
+            match &stmt.kind {
+                StmtKind::Let(name, expr) => {
+                    let ty = self.infer_expr_type(expr, local_types, call_sites);
                     local_types.insert(name.clone(), ty);
                 }
-                Stmt::Return(expr) => {
-                    return self.infer_expr_type(expr, &local_types);
+                StmtKind::Return(expr) => {
+                    let ty = self.infer_expr_type(expr, local_types, call_sites);
+                    returns.push((ty, stmt.span));
+                }
+                // Assignments must keep the type of the original binding.
+                StmtKind::Assign(_, _, expr) | StmtKind::Expr(expr) => {
+                    self.infer_expr_type(expr, local_types, call_sites);
+                }
+                StmtKind::IndexAssign(_, indices, _, expr) => {
+                    for index in indices {
+                        self.infer_expr_type(index, local_types, call_sites);
+                    }
+                    self.infer_expr_type(expr, local_types, call_sites);
+                }
+                StmtKind::If(cond, then_body, else_body) => {
+                    self.infer_expr_type(cond, local_types, call_sites);
+                    for body in std::iter::once(then_body).chain(else_body) {
+                        self.infer_block_types(body, &mut local_types.clone(), returns, call_sites);
+                    }
+                }
+                StmtKind::While(cond, body) => {
+                    self.infer_expr_type(cond, local_types, call_sites);
+                    self.infer_block_types(body, &mut local_types.clone(), returns, call_sites);
+                }
+                StmtKind::For(var, start, end, body) => {
+                    self.infer_expr_type(start, local_types, call_sites);
+                    self.infer_expr_type(end, local_types, call_sites);
+                    let mut body_types = local_types.clone();
+                    body_types.insert(var.clone(), ValueType::Scalar);
+                    self.infer_block_types(body, &mut body_types, returns, call_sites);
                 }
+                StmtKind::Break | StmtKind::Continue => {}
             }
         }
-        FunctionReturnType::Scalar // Default
     }
 
-    fn infer_expr_type(&self, expr: &Expr, locals: &HashMap<String, FunctionReturnType>) -> FunctionReturnType {
-        match expr {
-            Expr::Number(_) => FunctionReturnType::Scalar,
-            Expr::MatrixLiteral(_) => FunctionReturnType::Matrix,
-            Expr::Identifier(name) => *locals.get(name).unwrap_or(&FunctionReturnType::Scalar),
-            Expr::BinaryOp(left, _, right) => {
-                let lhs = self.infer_expr_type(left, locals);
-                let rhs = self.infer_expr_type(right, locals);
-                if lhs == FunctionReturnType::Matrix || rhs == FunctionReturnType::Matrix {
-                    FunctionReturnType::Matrix
+    fn infer_expr_type(&self, expr: &Expr, locals: &HashMap<String, ValueType>, call_sites: &mut Vec<(String, Vec<ValueType>)>) -> ValueType {
+        match &expr.kind {
+            ExprKind::Number(_) | ExprKind::Integer(_) => ValueType::Scalar,
+            ExprKind::Bool(_) => ValueType::Bool,
+            ExprKind::MatrixLiteral(rows) => {
+                for elem in rows.iter().flatten() {
+                    self.infer_expr_type(elem, locals, call_sites);
+                }
+                ValueType::Matrix
+            }
+            ExprKind::Identifier(name) => *locals.get(name).unwrap_or(&ValueType::Scalar),
+            ExprKind::Transpose(expr) => self.infer_expr_type(expr, locals, call_sites),
+            ExprKind::Index(target, indices) => {
+                self.infer_expr_type(target, locals, call_sites);
+                for index in indices {
+                    self.infer_expr_type(index, locals, call_sites);
+                }
+                ValueType::Scalar
+            }
+            ExprKind::Slice(target, subscripts) => {
+                self.infer_expr_type(target, locals, call_sites);
+                for subscript in subscripts {
+                    match subscript {
+                        Subscript::Point(index) => {
+                            self.infer_expr_type(index, locals, call_sites);
+                        }
+                        Subscript::Range { start, end, step } => {
+                            for bound in [start, end, step].into_iter().flatten() {
+                                self.infer_expr_type(bound, locals, call_sites);
+                            }
+                        }
+                    }
+                }
+                ValueType::Matrix
+            }
+            ExprKind::Call(name, args) => {
+                let arg_types = args.iter().map(|arg| self.infer_expr_type(arg, locals, call_sites)).collect();
+                call_sites.push((name.clone(), arg_types));
+                self.signatures.get(name).map(|s| s.ret).unwrap_or(ValueType::Scalar)
+            }
+            ExprKind::UnaryOp(UnaryOp::Not, expr) => {
+                self.infer_expr_type(expr, locals, call_sites);
+                ValueType::Bool
+            }
+            ExprKind::UnaryOp(UnaryOp::Neg, expr) => self.infer_expr_type(expr, locals, call_sites),
+            ExprKind::If(cond, then_expr, else_expr) => {
+                self.infer_expr_type(cond, locals, call_sites);
+                self.infer_expr_type(else_expr, locals, call_sites);
+                self.infer_expr_type(then_expr, locals, call_sites)
+            }
+            ExprKind::BinaryOp(left, op, right) => {
+                // A matrix operand makes the result a matrix; scalars broadcast over its elements.
+                let lhs = self.infer_expr_type(left, locals, call_sites);
+                let rhs = self.infer_expr_type(right, locals, call_sites);
+                if op.is_comparison() || *op == Op::And || *op == Op::Or {
+                    ValueType::Bool
+                } else if *op == Op::MatMul || lhs == ValueType::Matrix || rhs == ValueType::Matrix {
+                    ValueType::Matrix
                 } else {
-                    FunctionReturnType::Scalar
+                    ValueType::Scalar
                 }
             }
         }
@@ -117,8 +455,15 @@ impl<'ctx> CodeGen<'ctx> {
 
     /// Compiles a statement.
     fn compile_stmt(&mut self, stmt: &Stmt) -> Result<()> {
-        match stmt {
-            Stmt::Let(name, expr) => {
+        let outer = std::mem::replace(&mut self.span, stmt.span);
+        let result = self.compile_stmt_kind(stmt);
+        self.span = outer;
+        result
+    }
+
+    fn compile_stmt_kind(&mut self, stmt: &Stmt) -> Result<()> {
+        match &stmt.kind {
+            StmtKind::Let(name, expr) => {
                 let val = self.compile_expr(expr)?;
                 let ty = val.get_type();
                 // Create alloca
@@ -127,14 +472,214 @@ impl<'ctx> CodeGen<'ctx> {
                 self.variables.insert(name.clone(), (alloca, ty));
                 Ok(())
             }
-            Stmt::Return(expr) => {
+            StmtKind::Assign(name, op, expr) => {
+                let (ptr, ty) = match self.variables.get(name) {
+                    Some(var) => *var,
+                    None => return Err(CompileError::UndefinedVariable { name: name.clone(), span: self.span }),
+                };
+                let val = match op {
+                    Some(op) => {
+                        let current = Expr::new(ExprKind::Identifier(name.clone()), stmt.span);
+                        let kind = ExprKind::BinaryOp(Box::new(current), op.clone(), Box::new(expr.clone()));
+                        self.compile_expr(&Expr::new(kind, stmt.span))?
+                    }
+                    None => self.compile_expr(expr)?,
+                };
+                if val.get_type() != ty {
+                    return Err(self.type_error(format!(
+                        "Cannot assign a {} value to `{}`, which holds a {}",
+                        type_name(val.get_type()),
+                        name,
+                        type_name(ty)
+                    )));
+                }
+                self.builder.build_store(ptr, val)?;
+                Ok(())
+            }
+            StmtKind::IndexAssign(name, indices, op, expr) => {
+                let target = self.compile_expr(&Expr::new(ExprKind::Identifier(name.clone()), stmt.span))?;
+                if !target.is_pointer_value() {
+                    return Err(self.type_error("Cannot index into a scalar"));
+                }
+                let elem_ptr = self.compile_element_ptr(target.into_pointer_value(), indices)?;
+
                 let val = self.compile_expr(expr)?;
+                if !val.is_float_value() {
+                    return Err(self.type_error(format!("Cannot store a {} value into an element of `{}`", type_name(val.get_type()), name)));
+                }
+                let val = match op {
+                    Some(op) => {
+                        let current = self.builder.build_load(self.context.f64_type(), elem_ptr, "elem")?.into_float_value();
+                        self.build_scalar_op(op, current, val.into_float_value())?
+                    }
+                    None => val.into_float_value(),
+                };
+                self.builder.build_store(elem_ptr, val)?;
+                Ok(())
+            }
+            StmtKind::Return(expr) => {
+                let val = self.compile_expr(expr)?;
+                let fn_val = self.current_function();
+                if fn_val.get_type().get_return_type() != Some(val.get_type()) {
+                    return Err(self.type_error(format!("Function `{}` returns values of different types", fn_val.get_name().to_string_lossy())));
+                }
                 self.builder.build_return(Some(&val))?;
+
+                // Anything after a return is unreachable, but still needs a block to live in.
+                let after = self.context.append_basic_block(fn_val, "after_return");
+                self.builder.position_at_end(after);
+                Ok(())
+            }
+            StmtKind::Expr(expr) => {
+                self.compile_expr(expr)?;
+                Ok(())
+            }
+            StmtKind::If(cond, then_body, else_body) => {
+                let cond = self.compile_condition(cond)?;
+                let function = self.current_function();
+
+                let then_block = self.context.append_basic_block(function, "then");
+                let else_block = self.context.append_basic_block(function, "else");
+                let merge_block = self.context.append_basic_block(function, "ifcont");
+                self.builder.build_conditional_branch(cond, then_block, else_block)?;
+
+                self.builder.position_at_end(then_block);
+                self.compile_block(then_body)?;
+                self.build_branch_unless_terminated(merge_block)?;
+
+                self.builder.position_at_end(else_block);
+                if let Some(else_body) = else_body {
+                    self.compile_block(else_body)?;
+                }
+                self.build_branch_unless_terminated(merge_block)?;
+
+                self.builder.position_at_end(merge_block);
+                Ok(())
+            }
+            StmtKind::While(cond, body) => {
+                let function = self.current_function();
+                let cond_block = self.context.append_basic_block(function, "while_cond");
+                let body_block = self.context.append_basic_block(function, "while_body");
+                let end_block = self.context.append_basic_block(function, "while_end");
+                self.builder.build_unconditional_branch(cond_block)?;
+
+                self.builder.position_at_end(cond_block);
+                let cond = self.compile_condition(cond)?;
+                self.builder.build_conditional_branch(cond, body_block, end_block)?;
+
+                self.builder.position_at_end(body_block);
+                self.loops.push((cond_block, end_block));
+                self.compile_block(body)?;
+                self.loops.pop();
+                self.build_branch_unless_terminated(cond_block)?;
+
+                self.builder.position_at_end(end_block);
+                Ok(())
+            }
+            StmtKind::For(var, start, end, body) => {
+                let start = self.compile_range_bound(start)?;
+                let end = self.compile_range_bound(end)?;
+                let f64_type = self.context.f64_type();
+
+                // The loop runs on a hidden counter, so assigning to the loop
+                // variable inside the body does not change the iteration.
+                let counter = self.create_entry_block_alloca(&format!("{}_counter", var), f64_type.into());
+                self.builder.build_store(counter, start)?;
+
+                let function = self.current_function();
+                let cond_block = self.context.append_basic_block(function, "for_cond");
+                let body_block = self.context.append_basic_block(function, "for_body");
+                let step_block = self.context.append_basic_block(function, "for_step");
+                let end_block = self.context.append_basic_block(function, "for_end");
+                self.builder.build_unconditional_branch(cond_block)?;
+
+                self.builder.position_at_end(cond_block);
+                let i = self.builder.build_load(f64_type, counter, var)?.into_float_value();
+                let in_range = self.builder.build_float_compare(FloatPredicate::OLT, i, end, "in_range")?;
+                self.builder.build_conditional_branch(in_range, body_block, end_block)?;
+
+                // The loop variable is only visible inside the body.
+                self.builder.position_at_end(body_block);
+                let outer = self.variables.clone();
+                let var_alloca = self.create_entry_block_alloca(var, f64_type.into());
+                self.builder.build_store(var_alloca, i)?;
+                self.variables.insert(var.clone(), (var_alloca, f64_type.into()));
+                self.loops.push((step_block, end_block));
+                self.compile_block(body)?;
+                self.loops.pop();
+                self.variables = outer;
+                self.build_branch_unless_terminated(step_block)?;
+
+                self.builder.position_at_end(step_block);
+                let i = self.builder.build_load(f64_type, counter, var)?.into_float_value();
+                let next = self.builder.build_float_add(i, f64_type.const_float(1.0), "next")?;
+                self.builder.build_store(counter, next)?;
+                self.builder.build_unconditional_branch(cond_block)?;
+
+                self.builder.position_at_end(end_block);
+                Ok(())
+            }
+            StmtKind::Break | StmtKind::Continue => {
+                let is_break = stmt.kind == StmtKind::Break;
+                let (continue_block, break_block) = match self.loops.last() {
+                    Some(targets) => *targets,
+                    None => {
+                        let keyword = if is_break { "break" } else { "continue" };
+                        return Err(CompileError::OutsideLoop { keyword: keyword.to_string(), span: self.span });
+                    }
+                };
+                let target = if is_break { break_block } else { continue_block };
+                self.builder.build_unconditional_branch(target)?;
+
+                // Like after a return, the rest of the block is unreachable.
+                let after = self.context.append_basic_block(self.current_function(), "after_jump");
+                self.builder.position_at_end(after);
                 Ok(())
             }
         }
     }
 
+    /// Compiles a nested block. Variables declared inside it go out of scope at the end.
+    fn compile_block(&mut self, body: &[Stmt]) -> Result<()> {
+        let outer = self.variables.clone();
+        for stmt in body {
+            self.compile_stmt(stmt)?;
+        }
+        self.variables = outer;
+        Ok(())
+    }
+
+    /// Compiles an `if` condition, which must be a boolean.
+    fn compile_condition(&mut self, cond: &Expr) -> Result<IntValue<'ctx>> {
+        let val = self.compile_expr(cond)?;
+        if !val.is_int_value() {
+            return Err(self.type_error(format!("Condition must be a boolean, found a {}", type_name(val.get_type()))));
+        }
+        Ok(val.into_int_value())
+    }
+
+    /// Compiles a bound of a `for` range, which must be a scalar.
+    fn compile_range_bound(&mut self, bound: &Expr) -> Result<FloatValue<'ctx>> {
+        let val = self.compile_expr(bound)?;
+        if !val.is_float_value() {
+            return Err(self.type_error(format!("Range bounds must be scalars, found a {}", type_name(val.get_type()))));
+        }
+        Ok(val.into_float_value())
+    }
+
+    /// Branches to `target` unless the current block already ends in a terminator.
+    fn build_branch_unless_terminated(&self, target: BasicBlock<'ctx>) -> Result<()> {
+        if self.builder.get_insert_block().unwrap().get_terminator().is_none() {
+            self.builder.build_unconditional_branch(target)?;
+        }
+        Ok(())
+    }
+
+    /// Builds a `TypeError` located at the node being compiled.
+    fn type_error(&self, message: impl Into<String>) -> CompileError {
+        CompileError::TypeError { message: message.into(), span: self.span }
+    }
+
     /// Helper to create alloca in the entry block.
     fn create_entry_block_alloca(&self, name: &str, ty: BasicTypeEnum<'ctx>) -> PointerValue<'ctx> {
         let builder = self.context.create_builder();
@@ -150,59 +695,364 @@ impl<'ctx> CodeGen<'ctx> {
 
     /// Compiles an expression.
     fn compile_expr(&mut self, expr: &Expr) -> Result<BasicValueEnum<'ctx>> {
-        match expr {
-            Expr::Number(n) => Ok(self.context.f64_type().const_float(*n).into()),
-            Expr::Identifier(name) => {
+        let outer = std::mem::replace(&mut self.span, expr.span);
+        let result = self.compile_expr_kind(expr);
+        self.span = outer;
+        result
+    }
+
+    fn compile_expr_kind(&mut self, expr: &Expr) -> Result<BasicValueEnum<'ctx>> {
+        match &expr.kind {
+            ExprKind::Number(n) => Ok(self.context.f64_type().const_float(*n).into()),
+            ExprKind::Integer(n) => Ok(self.context.f64_type().const_float(*n as f64).into()),
+            ExprKind::Bool(b) => Ok(self.context.bool_type().const_int(*b as u64, false).into()),
+            ExprKind::Identifier(name) => {
                 match self.variables.get(name) {
                     Some((ptr, ty)) => {
                          let val = self.builder.build_load(*ty, *ptr, name)?;
                          Ok(val)
                     }
-                    None => bail!("Variable not found: {}", name),
+                    None => match named_constant(name) {
+                        Some(n) => Ok(self.context.f64_type().const_float(n).into()),
+                        None => Err(CompileError::UndefinedVariable { name: name.clone(), span: self.span }),
+                    },
                 }
             }
-            Expr::BinaryOp(left, op, right) => {
+            ExprKind::BinaryOp(left, op @ (Op::And | Op::Or), right) => self.compile_logical(op, left, right),
+            ExprKind::BinaryOp(left, op, right) if op.is_comparison() => {
+                let lhs = self.compile_expr(left)?;
+                let rhs = self.compile_expr(right)?;
+                self.compile_comparison(op, lhs, rhs)
+            }
+            ExprKind::UnaryOp(UnaryOp::Not, expr) => {
+                let val = self.compile_expr(expr)?;
+                if !val.is_int_value() {
+                    return Err(self.type_error(format!("Operator ! requires a boolean operand, found a {}", type_name(val.get_type()))));
+                }
+                Ok(self.builder.build_not(val.into_int_value(), "nottmp")?.into())
+            }
+            ExprKind::UnaryOp(UnaryOp::Neg, expr) => {
+                let val = self.compile_expr(expr)?;
+                if val.is_float_value() {
+                    Ok(self.builder.build_float_neg(val.into_float_value(), "negtmp")?.into())
+                } else if val.is_pointer_value() {
+                    self.compile_negate(val.into_pointer_value())
+                } else {
+                    Err(self.type_error("Operator - is not defined for booleans"))
+                }
+            }
+            ExprKind::If(cond, then_expr, else_expr) => self.compile_if_expr(cond, then_expr, else_expr),
+            ExprKind::BinaryOp(left, op, right) => {
                 let lhs = self.compile_expr(left)?;
                 let rhs = self.compile_expr(right)?;
 
                 // Check types
-                if lhs.is_float_value() && rhs.is_float_value() {
-                    let lhs_float = lhs.into_float_value();
-                    let rhs_float = rhs.into_float_value();
-                     let res = match op {
-                        Op::Add => self.builder.build_float_add(lhs_float, rhs_float, "addtmp")?,
-                        Op::Subtract => self.builder.build_float_sub(lhs_float, rhs_float, "subtmp")?,
-                        Op::Multiply => self.builder.build_float_mul(lhs_float, rhs_float, "multmp")?,
-                        Op::Divide => self.builder.build_float_div(lhs_float, rhs_float, "divtmp")?,
-                    };
+                if lhs.is_int_value() || rhs.is_int_value() {
+                    Err(self.type_error(format!("Operator {} is not defined for booleans", op)))
+                } else if lhs.is_float_value() && rhs.is_float_value() {
+                    let res = self.build_scalar_op(op, lhs.into_float_value(), rhs.into_float_value())?;
                     Ok(res.into())
                 } else if lhs.is_pointer_value() && rhs.is_pointer_value() {
-                     // Matrix + Matrix
+                     // Matrix op Matrix: `*` is the matrix product, element-wise products use `.*`
                      match op {
-                         Op::Add => self.compile_matrix_add(lhs.into_pointer_value(), rhs.into_pointer_value()),
-                         _ => bail!("Operator {:?} not supported for matrices yet", op),
+                         Op::Add | Op::Subtract | Op::ElemMul | Op::ElemDiv | Op::ElemPower => {
+                             self.compile_elementwise(op, lhs.into_pointer_value(), rhs.into_pointer_value())
+                         }
+                         Op::Multiply | Op::MatMul => self.compile_matmul(op, lhs.into_pointer_value(), rhs.into_pointer_value()),
+                         Op::Divide => Err(self.type_error("Operator / is not defined between matrices, use ./ for element-wise division")),
+                         Op::Power => Err(self.type_error("Operator ^ requires a scalar exponent, use .^ for element-wise powers")),
+                         _ => unreachable!("comparison and logical operators are compiled separately"),
                      }
+                } else if lhs.is_pointer_value() && rhs.is_float_value() && *op == Op::Power {
+                    self.compile_matrix_power(lhs.into_pointer_value(), rhs.into_float_value())
+                } else if lhs.is_pointer_value() && rhs.is_float_value() {
+                    // Matrix op Scalar
+                    self.compile_scalar_broadcast(op, lhs.into_pointer_value(), rhs.into_float_value(), false)
+                } else if lhs.is_float_value() && rhs.is_pointer_value() {
+                    // Scalar op Matrix
+                    self.compile_scalar_broadcast(op, rhs.into_pointer_value(), lhs.into_float_value(), true)
                 } else {
-                    bail!("Type mismatch in binary operation")
+                    Err(self.type_error("Type mismatch in binary operation"))
                 }
             }
-            Expr::MatrixLiteral(rows) => {
+            ExprKind::MatrixLiteral(rows) => {
                 self.compile_matrix_literal(rows)
             }
+            ExprKind::Transpose(expr) => {
+                let val = self.compile_expr(expr)?;
+                if val.is_pointer_value() {
+                    self.compile_transpose(val.into_pointer_value())
+                } else {
+                    // A scalar is its own transpose.
+                    Ok(val)
+                }
+            }
+            ExprKind::Index(target, indices) => {
+                let val = self.compile_expr(target)?;
+                if !val.is_pointer_value() {
+                    return Err(self.type_error("Cannot index into a scalar"));
+                }
+                self.compile_index(val.into_pointer_value(), indices)
+            }
+            ExprKind::Call(name, args) => self.compile_call(name, args),
+            ExprKind::Slice(target, subscripts) => {
+                let val = self.compile_expr(target)?;
+                if !val.is_pointer_value() {
+                    return Err(self.type_error("Cannot slice a scalar"));
+                }
+                self.compile_slice(val.into_pointer_value(), subscripts)
+            }
         }
     }
 
-    fn compile_matrix_literal(&mut self, rows: &Vec<Vec<Expr>>) -> Result<BasicValueEnum<'ctx>> {
-         let num_rows = rows.len() as u64;
-         if num_rows == 0 {
-             bail!("Empty matrix literal");
+    /// Generates a call to another function in the program, a host function or an
+    /// extern function, propagating any runtime error raised by the callee.
+    fn compile_call(&mut self, name: &str, args: &[Expr]) -> Result<BasicValueEnum<'ctx>> {
+        let callee = match (self.module.get_function(name), self.signatures.get(name)) {
+            (Some(callee), Some(_)) => callee,
+            _ => return Err(CompileError::UndefinedFunction { name: name.to_string(), span: self.span }),
+        };
+
+        let param_types = callee.get_type().get_param_types();
+        if param_types.len() != args.len() {
+            return Err(CompileError::ArgumentCount { name: name.to_string(), expected: param_types.len(), found: args.len(), span: self.span });
+        }
+
+        let mut arg_values = Vec::with_capacity(args.len());
+        for (i, (arg, param_type)) in args.iter().zip(param_types).enumerate() {
+            let val = self.compile_expr(arg)?;
+            if val.get_type() != param_type {
+                return Err(self.type_error(format!(
+                    "Argument {} of `{}` must be a {}, found a {}",
+                    i + 1,
+                    name,
+                    type_name(param_type),
+                    type_name(val.get_type())
+                )));
+            }
+            arg_values.push(val.into());
+        }
+
+        let call = self.builder.build_call(callee, &arg_values, "calltmp")?;
+        let result = call.try_as_basic_value().left().unwrap();
+
+        // If the callee raised an error it returned early with a zero value; stop here too.
+        let pending_fn = self.module.get_function(runtime::PENDING_FN).unwrap();
+        let pending = self.builder.build_call(pending_fn, &[], "pending")?.try_as_basic_value().left().unwrap();
+        let ok = self.builder.build_not(pending.into_int_value(), "no_error")?;
+        self.build_return_unless(ok)?;
+
+        Ok(result)
+    }
+
+    /// Applies a scalar arithmetic operator to two f64 values.
+    fn build_scalar_op(&self, op: &Op, lhs: FloatValue<'ctx>, rhs: FloatValue<'ctx>) -> Result<FloatValue<'ctx>> {
+        let res = match op {
+            Op::Add => self.builder.build_float_add(lhs, rhs, "addtmp")?,
+            Op::Subtract => self.builder.build_float_sub(lhs, rhs, "subtmp")?,
+            Op::Multiply | Op::ElemMul => self.builder.build_float_mul(lhs, rhs, "multmp")?,
+            Op::Divide | Op::ElemDiv => self.builder.build_float_div(lhs, rhs, "divtmp")?,
+            Op::Power | Op::ElemPower => self.build_float_intrinsic("llvm.pow", &[lhs, rhs], "powtmp")?,
+            Op::MatMul => return Err(self.type_error("Operator @ requires matrix operands")),
+            _ => return Err(self.type_error(format!("Operator {} does not produce a scalar", op))),
+        };
+        Ok(res)
+    }
+
+    /// Generates a comparison between two scalars, or `==` / `!=` between two booleans.
+    fn compile_comparison(&self, op: &Op, lhs: BasicValueEnum<'ctx>, rhs: BasicValueEnum<'ctx>) -> Result<BasicValueEnum<'ctx>> {
+        if lhs.is_float_value() && rhs.is_float_value() {
+            let predicate = match op {
+                Op::Equal => FloatPredicate::OEQ,
+                Op::NotEqual => FloatPredicate::UNE,
+                Op::Less => FloatPredicate::OLT,
+                Op::LessEqual => FloatPredicate::OLE,
+                Op::Greater => FloatPredicate::OGT,
+                Op::GreaterEqual => FloatPredicate::OGE,
+                _ => unreachable!("not a comparison operator: {}", op),
+            };
+            let res = self.builder.build_float_compare(predicate, lhs.into_float_value(), rhs.into_float_value(), "cmptmp")?;
+            return Ok(res.into());
+        }
+
+        if lhs.is_int_value() && rhs.is_int_value() && matches!(op, Op::Equal | Op::NotEqual) {
+            let predicate = if *op == Op::Equal { IntPredicate::EQ } else { IntPredicate::NE };
+            let res = self.builder.build_int_compare(predicate, lhs.into_int_value(), rhs.into_int_value(), "cmptmp")?;
+            return Ok(res.into());
+        }
+
+        Err(self.type_error(format!(
+            "Operator {} cannot compare a {} with a {}",
+            op,
+            type_name(lhs.get_type()),
+            type_name(rhs.get_type())
+        )))
+    }
+
+    /// Generates a short-circuiting `&&` or `||`: the right operand is only
+    /// evaluated when the left one does not already decide the result.
+    fn compile_logical(&mut self, op: &Op, left: &Expr, right: &Expr) -> Result<BasicValueEnum<'ctx>> {
+        let function = self.current_function();
+        let lhs = self.compile_condition(left)?;
+        let lhs_block = self.builder.get_insert_block().unwrap();
+
+        let rhs_block = self.context.append_basic_block(function, "logic_rhs");
+        let merge_block = self.context.append_basic_block(function, "logic_end");
+        match op {
+            Op::And => self.builder.build_conditional_branch(lhs, rhs_block, merge_block)?,
+            _ => self.builder.build_conditional_branch(lhs, merge_block, rhs_block)?,
+        };
+
+        self.builder.position_at_end(rhs_block);
+        let rhs = self.compile_condition(right)?;
+        let rhs_end = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(merge_block)?;
+
+        self.builder.position_at_end(merge_block);
+        let phi = self.builder.build_phi(self.context.bool_type(), "logictmp")?;
+        phi.add_incoming(&[(&lhs, lhs_block), (&rhs, rhs_end)]);
+        Ok(phi.as_basic_value())
+    }
+
+    /// Generates `if cond { a } else { b }`; both branches must have the same type.
+    fn compile_if_expr(&mut self, cond: &Expr, then_expr: &Expr, else_expr: &Expr) -> Result<BasicValueEnum<'ctx>> {
+        let cond = self.compile_condition(cond)?;
+        let function = self.current_function();
+
+        let then_block = self.context.append_basic_block(function, "then");
+        let else_block = self.context.append_basic_block(function, "else");
+        let merge_block = self.context.append_basic_block(function, "ifcont");
+        self.builder.build_conditional_branch(cond, then_block, else_block)?;
+
+        // Either branch may contain calls that add blocks, so take the incoming
+        // block for the phi from wherever each branch ends.
+        self.builder.position_at_end(then_block);
+        let then_val = self.compile_expr(then_expr)?;
+        let then_end = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(merge_block)?;
+
+        self.builder.position_at_end(else_block);
+        let else_val = self.compile_expr(else_expr)?;
+        let else_end = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(merge_block)?;
+
+        if then_val.get_type() != else_val.get_type() {
+            return Err(self.type_error(format!(
+                "Branches of `if` have different types: {} and {}",
+                type_name(then_val.get_type()),
+                type_name(else_val.get_type())
+            )));
+        }
+
+        self.builder.position_at_end(merge_block);
+        let phi = self.builder.build_phi(then_val.get_type(), "iftmp")?;
+        phi.add_incoming(&[(&then_val, then_end), (&else_val, else_end)]);
+        Ok(phi.as_basic_value())
+    }
+
+    /// Generates `scalar op matrix` (or `matrix op scalar` when `scalar_on_left` is false)
+    /// by applying `op` between the scalar and every element.
+    fn compile_scalar_broadcast(&mut self, op: &Op, matrix_ptr: PointerValue<'ctx>, scalar: FloatValue<'ctx>, scalar_on_left: bool) -> Result<BasicValueEnum<'ctx>> {
+        if *op == Op::MatMul {
+            return Err(self.type_error("Operator @ requires matrix operands"));
+        }
+        if *op == Op::Power {
+            return Err(self.type_error("Operator ^ cannot raise a scalar to a matrix power, use .^ for element-wise powers"));
+        }
+
+        let f64_type = self.context.f64_type();
+
+        let (rows, cols) = self.load_matrix_dims(matrix_ptr, "mat")?;
+        let total_size = self.builder.build_int_mul(rows, cols, "total_size")?;
+        let res_data_ptr = self.builder.build_array_malloc(f64_type, total_size, "res_data")?;
+        let data_ptr = self.load_matrix_data(matrix_ptr, "mat")?;
+
+        self.build_counted_loop(total_size, "i", |cg, i| {
+            let elem = cg.load_element(data_ptr, i, "elem")?;
+            let res_val = if scalar_on_left {
+                cg.build_scalar_op(op, scalar, elem)?
+            } else {
+                cg.build_scalar_op(op, elem, scalar)?
+            };
+
+            let res_elem_ptr = unsafe { cg.builder.build_gep(f64_type, res_data_ptr, &[i], "res_elem_ptr")? };
+            cg.builder.build_store(res_elem_ptr, res_val)?;
+            Ok(())
+        })?;
+
+        let res_matrix_ptr = self.build_matrix_struct(res_data_ptr, rows, cols)?;
+        Ok(res_matrix_ptr.into())
+    }
+
+    /// Generates `-matrix` by negating every element.
+    fn compile_negate(&mut self, matrix_ptr: PointerValue<'ctx>) -> Result<BasicValueEnum<'ctx>> {
+        let f64_type = self.context.f64_type();
+
+        let (rows, cols) = self.load_matrix_dims(matrix_ptr, "mat")?;
+        let total_size = self.builder.build_int_mul(rows, cols, "total_size")?;
+        let res_data_ptr = self.builder.build_array_malloc(f64_type, total_size, "res_data")?;
+        let data_ptr = self.load_matrix_data(matrix_ptr, "mat")?;
+
+        self.build_counted_loop(total_size, "i", |cg, i| {
+            let elem = cg.load_element(data_ptr, i, "elem")?;
+            let res_val = cg.builder.build_float_neg(elem, "negtmp")?;
+            let res_elem_ptr = unsafe { cg.builder.build_gep(f64_type, res_data_ptr, &[i], "res_elem_ptr")? };
+            cg.builder.build_store(res_elem_ptr, res_val)?;
+            Ok(())
+        })?;
+
+        let res_matrix_ptr = self.build_matrix_struct(res_data_ptr, rows, cols)?;
+        Ok(res_matrix_ptr.into())
+    }
+
+    /// Generates a matrix literal. Literals of plain scalars have a shape known at compile
+    /// time; literals with matrix-valued elements are assembled as block matrices.
+    fn compile_matrix_literal(&mut self, rows: &[Vec<Expr>]) -> Result<BasicValueEnum<'ctx>> {
+         if rows.is_empty() {
+             return Err(CompileError::ShapeError { message: "Empty matrix literal".to_string(), span: self.span });
          }
+
+         let mut values = Vec::with_capacity(rows.len());
+         for row in rows {
+             let mut row_values = Vec::with_capacity(row.len());
+             for expr in row {
+                 let val = self.compile_expr(expr)?;
+                 if val.is_int_value() {
+                     return Err(self.type_error("Matrix elements must be numbers or matrices"));
+                 }
+                 row_values.push(val);
+             }
+             values.push(row_values);
+         }
+
+         // The parser only reads `[[1, 2], [3, 4]]` as rows when every element is a
+         // bracketed row, so in `[[1, 2], r]` the row would be joined to `r` rather than
+         // stacked on it. Reject that instead of guessing which was meant.
+         if let [row] = rows {
+             let has_row_literal = row.iter().any(|e| matches!(&e.kind, ExprKind::MatrixLiteral(inner) if inner.len() == 1));
+             let has_matrix_value = row
+                 .iter()
+                 .zip(&values[0])
+                 .any(|(e, val)| val.is_pointer_value() && !matches!(e.kind, ExprKind::MatrixLiteral(_)));
+             if has_row_literal && has_matrix_value {
+                 return Err(CompileError::ShapeError {
+                     message: "Cannot mix nested rows with matrix values in a literal, use `;` to stack rows or `,` inside one row".to_string(),
+                     span: self.span,
+                 });
+             }
+         }
+
+         if values.iter().flatten().any(|val| val.is_pointer_value()) {
+             return self.compile_block_matrix(&values);
+         }
+
+         let num_rows = rows.len() as u64;
          let num_cols = rows[0].len() as u64;
 
          // Verify all rows have same length
          for row in rows {
              if row.len() as u64 != num_cols {
-                 bail!("Matrix rows must have same length");
+                 return Err(CompileError::ShapeError { message: "Matrix rows must have same length".to_string(), span: self.span });
              }
          }
 
@@ -217,12 +1067,8 @@ impl<'ctx> CodeGen<'ctx> {
          let data_ptr = self.builder.build_array_malloc(f64_type, total_size_val, "matrix_data")?;
 
          // Populate data
-         for (i, row) in rows.iter().enumerate() {
-             for (j, expr) in row.iter().enumerate() {
-                 let val = self.compile_expr(expr)?;
-                 if !val.is_float_value() {
-                     bail!("Matrix elements must be numbers");
-                 }
+         for (i, row) in values.iter().enumerate() {
+             for (j, val) in row.iter().enumerate() {
                  let float_val = val.into_float_value();
 
                  // index = i * cols + j
@@ -241,117 +1087,767 @@ impl<'ctx> CodeGen<'ctx> {
          let matrix_ptr = self.builder.build_malloc(self.matrix_type, "matrix_struct")?;
 
          // Store data ptr
-         let data_field_ptr = self.builder.build_struct_gep(self.matrix_type, matrix_ptr, 0, "data_field")
-            .map_err(|_| anyhow!("Struct GEP failed"))?;
+         let data_field_ptr = self.builder.build_struct_gep(self.matrix_type, matrix_ptr, 0, "data_field")?;
          self.builder.build_store(data_field_ptr, data_ptr)?;
 
          // Store rows
-         let rows_field_ptr = self.builder.build_struct_gep(self.matrix_type, matrix_ptr, 1, "rows_field")
-            .map_err(|_| anyhow!("Struct GEP failed"))?;
+         let rows_field_ptr = self.builder.build_struct_gep(self.matrix_type, matrix_ptr, 1, "rows_field")?;
          self.builder.build_store(rows_field_ptr, i64_type.const_int(num_rows, false))?;
 
          // Store cols
-         let cols_field_ptr = self.builder.build_struct_gep(self.matrix_type, matrix_ptr, 2, "cols_field")
-             .map_err(|_| anyhow!("Struct GEP failed"))?;
+         let cols_field_ptr = self.builder.build_struct_gep(self.matrix_type, matrix_ptr, 2, "cols_field")?;
          self.builder.build_store(cols_field_ptr, i64_type.const_int(num_cols, false))?;
 
          Ok(matrix_ptr.into())
     }
 
-    fn compile_matrix_add(&mut self, lhs_ptr: PointerValue<'ctx>, rhs_ptr: PointerValue<'ctx>) -> Result<BasicValueEnum<'ctx>> {
-        let i64_type = self.context.i64_type();
+    /// Generates an element-wise `lhs op rhs` for two matrices of the same shape.
+    fn compile_elementwise(&mut self, op: &Op, lhs_ptr: PointerValue<'ctx>, rhs_ptr: PointerValue<'ctx>) -> Result<BasicValueEnum<'ctx>> {
         let f64_type = self.context.f64_type();
 
-        // Load dimensions from LHS (Assume LHS and RHS dimensions match for now)
-        let rows_ptr = self.builder.build_struct_gep(self.matrix_type, lhs_ptr, 1, "rows_ptr")
-            .map_err(|_| anyhow!("GEP failed"))?;
-        let rows = self.builder.build_load(i64_type, rows_ptr, "rows")?.into_int_value();
+        let (rows, cols) = self.load_matrix_dims(lhs_ptr, "lhs")?;
+        let (rhs_rows, rhs_cols) = self.load_matrix_dims(rhs_ptr, "rhs")?;
 
-        let cols_ptr = self.builder.build_struct_gep(self.matrix_type, lhs_ptr, 2, "cols_ptr")
-             .map_err(|_| anyhow!("GEP failed"))?;
-        let cols = self.builder.build_load(i64_type, cols_ptr, "cols")?.into_int_value();
+        // Both operands must have the same shape, otherwise the loop below would
+        // read past the end of the smaller buffer.
+        let rows_eq = self.builder.build_int_compare(IntPredicate::EQ, rows, rhs_rows, "rows_eq")?;
+        let cols_eq = self.builder.build_int_compare(IntPredicate::EQ, cols, rhs_cols, "cols_eq")?;
+        let same_shape = self.builder.build_and(rows_eq, cols_eq, "same_shape")?;
+        self.build_runtime_check(same_shape, runtime::SHAPE_MISMATCH, &op.to_string(), [rows, cols, rhs_rows, rhs_cols])?;
 
-        let total_size = self.builder.build_int_mul(rows, cols, "total_size");
+        let total_size = self.builder.build_int_mul(rows, cols, "total_size")?;
 
         // Allocate result data
         let res_data_ptr = self.builder.build_array_malloc(f64_type, total_size, "res_data")?;
 
-        // Get data pointers
-        let lhs_data_ptr_ptr = self.builder.build_struct_gep(self.matrix_type, lhs_ptr, 0, "lhs_data_ptr")
-            .map_err(|_| anyhow!("GEP failed"))?;
-        let lhs_data_ptr = self.builder.build_load(f64_type.ptr_type(inkwell::AddressSpace::default()), lhs_data_ptr_ptr, "lhs_data")?.into_pointer_value();
+        let lhs_data_ptr = self.load_matrix_data(lhs_ptr, "lhs")?;
+        let rhs_data_ptr = self.load_matrix_data(rhs_ptr, "rhs")?;
+
+        self.build_counted_loop(total_size, "i", |cg, i| {
+            let lhs_val = cg.load_element(lhs_data_ptr, i, "lhs_val")?;
+            let rhs_val = cg.load_element(rhs_data_ptr, i, "rhs_val")?;
+            let res_val = cg.build_scalar_op(op, lhs_val, rhs_val)?;
+
+            let res_elem_ptr = unsafe { cg.builder.build_gep(f64_type, res_data_ptr, &[i], "res_elem_ptr")? };
+            cg.builder.build_store(res_elem_ptr, res_val)?;
+            Ok(())
+        })?;
+
+        let res_matrix_ptr = self.build_matrix_struct(res_data_ptr, rows, cols)?;
+        Ok(res_matrix_ptr.into())
+    }
+
+    /// Generates `lhs @ rhs` (or `lhs * rhs`): a `rows(lhs) x cols(rhs)` matrix product.
+    fn compile_matmul(&mut self, op: &Op, lhs_ptr: PointerValue<'ctx>, rhs_ptr: PointerValue<'ctx>) -> Result<BasicValueEnum<'ctx>> {
+        let f64_type = self.context.f64_type();
+
+        let (lhs_rows, lhs_cols) = self.load_matrix_dims(lhs_ptr, "lhs")?;
+        let (rhs_rows, rhs_cols) = self.load_matrix_dims(rhs_ptr, "rhs")?;
+
+        // The inner dimensions must agree, otherwise the k loop would read past either buffer.
+        let inner_ok = self.builder.build_int_compare(IntPredicate::EQ, lhs_cols, rhs_rows, "inner_ok")?;
+        self.build_runtime_check(inner_ok, runtime::SHAPE_MISMATCH, &op.to_string(), [lhs_rows, lhs_cols, rhs_rows, rhs_cols])?;
+
+        let lhs_data = self.load_matrix_data(lhs_ptr, "lhs")?;
+        let rhs_data = self.load_matrix_data(rhs_ptr, "rhs")?;
+
+        let total_size = self.builder.build_int_mul(lhs_rows, rhs_cols, "total_size")?;
+        let res_data = self.builder.build_array_malloc(f64_type, total_size, "res_data")?;
+
+        let acc = self.create_entry_block_alloca("acc", f64_type.into());
+
+        self.build_counted_loop(lhs_rows, "row", |cg, i| {
+            cg.build_counted_loop(rhs_cols, "col", |cg, j| {
+                cg.builder.build_store(acc, f64_type.const_zero())?;
+
+                cg.build_counted_loop(lhs_cols, "inner", |cg, k| {
+                    // acc += lhs[i * lhs_cols + k] * rhs[k * rhs_cols + j]
+                    let lhs_row_start = cg.builder.build_int_mul(i, lhs_cols, "lhs_row_start")?;
+                    let lhs_index = cg.builder.build_int_add(lhs_row_start, k, "lhs_index")?;
+                    let lhs_val = cg.load_element(lhs_data, lhs_index, "lhs_val")?;
+
+                    let rhs_row_start = cg.builder.build_int_mul(k, rhs_cols, "rhs_row_start")?;
+                    let rhs_index = cg.builder.build_int_add(rhs_row_start, j, "rhs_index")?;
+                    let rhs_val = cg.load_element(rhs_data, rhs_index, "rhs_val")?;
+
+                    let product = cg.builder.build_float_mul(lhs_val, rhs_val, "product")?;
+                    let sum = cg.builder.build_load(f64_type, acc, "acc_val")?.into_float_value();
+                    let sum = cg.builder.build_float_add(sum, product, "acc_next")?;
+                    cg.builder.build_store(acc, sum)?;
+                    Ok(())
+                })?;
+
+                let res_row_start = cg.builder.build_int_mul(i, rhs_cols, "res_row_start")?;
+                let res_index = cg.builder.build_int_add(res_row_start, j, "res_index")?;
+                let res_elem_ptr = unsafe { cg.builder.build_gep(f64_type, res_data, &[res_index], "res_elem_ptr")? };
+                let sum = cg.builder.build_load(f64_type, acc, "acc_val")?;
+                cg.builder.build_store(res_elem_ptr, sum)?;
+                Ok(())
+            })
+        })?;
+
+        let res_matrix_ptr = self.build_matrix_struct(res_data, lhs_rows, rhs_cols)?;
+        Ok(res_matrix_ptr.into())
+    }
+
+    /// Generates a block matrix from rows of scalars (1x1 blocks) and matrices.
+    ///
+    /// Blocks in the same row must have the same number of rows, and every block row must
+    /// add up to the same number of columns; both are checked at runtime.
+    fn compile_block_matrix(&mut self, rows: &[Vec<BasicValueEnum<'ctx>>]) -> Result<BasicValueEnum<'ctx>> {
+        let f64_type = self.context.f64_type();
+        let i64_type = self.context.i64_type();
+        let zero = i64_type.const_zero();
+        let one = i64_type.const_int(1, false);
+
+        if rows.iter().any(|row| row.is_empty()) {
+            return Err(CompileError::ShapeError { message: "Matrix rows must not be empty".to_string(), span: self.span });
+        }
 
-        let rhs_data_ptr_ptr = self.builder.build_struct_gep(self.matrix_type, rhs_ptr, 0, "rhs_data_ptr")
-             .map_err(|_| anyhow!("GEP failed"))?;
-        let rhs_data_ptr = self.builder.build_load(f64_type.ptr_type(inkwell::AddressSpace::default()), rhs_data_ptr_ptr, "rhs_data")?.into_pointer_value();
+        let mut shapes = Vec::with_capacity(rows.len());
+        for row in rows {
+            let mut row_shapes = Vec::with_capacity(row.len());
+            for val in row {
+                let shape = if val.is_pointer_value() {
+                    self.load_matrix_dims(val.into_pointer_value(), "block")?
+                } else {
+                    (one, one)
+                };
+                row_shapes.push(shape);
+            }
+            shapes.push(row_shapes);
+        }
 
-        // Loop
-        let loop_block = self.context.append_basic_block(self.builder.get_insert_block().unwrap().get_parent().unwrap(), "loop");
-        let after_block = self.context.append_basic_block(self.builder.get_insert_block().unwrap().get_parent().unwrap(), "after_loop");
+        // Check the shapes before writing anything, so a mismatch never writes out of bounds.
+        let mut total_rows = zero;
+        let mut total_cols = None;
+        for row_shapes in &shapes {
+            let height = row_shapes[0].0;
+            let mut width = row_shapes[0].1;
+            for &(block_rows, block_cols) in &row_shapes[1..] {
+                let same_height = self.builder.build_int_compare(IntPredicate::EQ, block_rows, height, "same_height")?;
+                self.build_runtime_check(same_height, runtime::SHAPE_MISMATCH, ",", [height, width, block_rows, block_cols])?;
+                width = self.builder.build_int_add(width, block_cols, "block_row_width")?;
+            }
 
-        // Incoming value for phi requires predecessors.
-        // We need to branch to loop_block from current block.
-        let entry_block = self.builder.get_insert_block().unwrap();
-        self.builder.build_unconditional_branch(loop_block)?;
+            match total_cols {
+                None => total_cols = Some(width),
+                Some(cols) => {
+                    let same_width = self.builder.build_int_compare(IntPredicate::EQ, width, cols, "same_width")?;
+                    self.build_runtime_check(same_width, runtime::SHAPE_MISMATCH, ";", [total_rows, cols, height, width])?;
+                }
+            }
+            total_rows = self.builder.build_int_add(total_rows, height, "block_total_rows")?;
+        }
+        let total_cols = total_cols.unwrap();
 
-        self.builder.position_at_end(loop_block);
+        let total_size = self.builder.build_int_mul(total_rows, total_cols, "total_size")?;
+        let res_data = self.builder.build_array_malloc(f64_type, total_size, "res_data")?;
 
-        let i = self.builder.build_phi(i64_type, "i")?;
-        // We will add incoming values later? No, we need to do it now or update it.
-        // i comes from entry (0) or loop (next_i).
-        i.add_incoming(&[(&i64_type.const_int(0, false), entry_block)]);
+        let mut row_offset = zero;
+        for (row, row_shapes) in rows.iter().zip(&shapes) {
+            let mut col_offset = zero;
+            for (val, &(block_rows, block_cols)) in row.iter().zip(row_shapes) {
+                if val.is_float_value() {
+                    let row_start = self.builder.build_int_mul(row_offset, total_cols, "res_row_start")?;
+                    let res_index = self.builder.build_int_add(row_start, col_offset, "res_index")?;
+                    let res_elem_ptr = unsafe { self.builder.build_gep(f64_type, res_data, &[res_index], "res_elem_ptr")? };
+                    self.builder.build_store(res_elem_ptr, val.into_float_value())?;
+                } else {
+                    // res[row_offset + i][col_offset + j] = block[i][j]
+                    let block_data = self.load_matrix_data(val.into_pointer_value(), "block")?;
+                    self.build_counted_loop(block_rows, "block_row", |cg, i| {
+                        cg.build_counted_loop(block_cols, "block_col", |cg, j| {
+                            let block_row_start = cg.builder.build_int_mul(i, block_cols, "block_row_start")?;
+                            let block_index = cg.builder.build_int_add(block_row_start, j, "block_index")?;
+                            let elem = cg.load_element(block_data, block_index, "block_val")?;
 
-        // Loop body
-        // Load A[i]
-        let lhs_elem_ptr = unsafe { self.builder.build_gep(f64_type, lhs_data_ptr, &[i.as_basic_value().into_int_value()], "lhs_elem_ptr")? };
-        let lhs_val = self.builder.build_load(f64_type, lhs_elem_ptr, "lhs_val")?.into_float_value();
+                            let res_row = cg.builder.build_int_add(row_offset, i, "res_row")?;
+                            let res_row_start = cg.builder.build_int_mul(res_row, total_cols, "res_row_start")?;
+                            let res_col = cg.builder.build_int_add(col_offset, j, "res_col")?;
+                            let res_index = cg.builder.build_int_add(res_row_start, res_col, "res_index")?;
+                            let res_elem_ptr = unsafe { cg.builder.build_gep(f64_type, res_data, &[res_index], "res_elem_ptr")? };
+                            cg.builder.build_store(res_elem_ptr, elem)?;
+                            Ok(())
+                        })
+                    })?;
+                }
+                col_offset = self.builder.build_int_add(col_offset, block_cols, "col_offset")?;
+            }
+            row_offset = self.builder.build_int_add(row_offset, row_shapes[0].0, "row_offset")?;
+        }
 
-        // Load B[i]
-        let rhs_elem_ptr = unsafe { self.builder.build_gep(f64_type, rhs_data_ptr, &[i.as_basic_value().into_int_value()], "rhs_elem_ptr")? };
-        let rhs_val = self.builder.build_load(f64_type, rhs_elem_ptr, "rhs_val")?.into_float_value();
+        let res_matrix_ptr = self.build_matrix_struct(res_data, total_rows, total_cols)?;
+        Ok(res_matrix_ptr.into())
+    }
 
-        // Add
-        let res_val = self.builder.build_float_add(lhs_val, rhs_val, "sum");
+    /// Generates `A ^ n` for a square matrix `A` and a non-negative integer `n` by
+    /// repeated squaring, so only `O(log n)` matrix products are needed.
+    fn compile_matrix_power(&mut self, matrix_ptr: PointerValue<'ctx>, exponent: FloatValue<'ctx>) -> Result<BasicValueEnum<'ctx>> {
+        let f64_type = self.context.f64_type();
+        let i64_type = self.context.i64_type();
+        let zero = i64_type.const_zero();
+        let op = Op::Power.to_string();
+
+        let (rows, cols) = self.load_matrix_dims(matrix_ptr, "base")?;
+        let square = self.builder.build_int_compare(IntPredicate::EQ, rows, cols, "square")?;
+        self.build_runtime_check(square, runtime::NON_SQUARE_MATRIX, &op, [rows, cols, zero, zero])?;
+
+        let is_integer = self.build_is_i64(exponent, "exp")?;
+        let non_negative = self.builder.build_float_compare(FloatPredicate::OGE, exponent, f64_type.const_zero(), "exp_non_negative")?;
+        let exponent_ok = self.builder.build_and(is_integer, non_negative, "exp_ok")?;
+        let exponent_bits = self.builder.build_bit_cast(exponent, i64_type, "exp_bits")?.into_int_value();
+        self.build_runtime_check(exponent_ok, runtime::INVALID_EXPONENT, &op, [exponent_bits, zero, zero, zero])?;
 
-        // Store Result[i]
-        let res_elem_ptr = unsafe { self.builder.build_gep(f64_type, res_data_ptr, &[i.as_basic_value().into_int_value()], "res_elem_ptr")? };
-        self.builder.build_store(res_elem_ptr, res_val)?;
+        let ptr_type: BasicTypeEnum<'ctx> = matrix_ptr.get_type().into();
+        let result = self.create_entry_block_alloca("pow_result", ptr_type);
+        let base = self.create_entry_block_alloca("pow_base", ptr_type);
+        let remaining = self.create_entry_block_alloca("pow_remaining", i64_type.into());
 
-        // Increment
-        let next_i = self.builder.build_int_add(i.as_basic_value().into_int_value(), i64_type.const_int(1, false), "next_i");
-        // Loop back
-        i.add_incoming(&[(&next_i, loop_block)]);
+        let identity = self.build_identity(rows)?;
+        self.builder.build_store(result, identity)?;
+        self.builder.build_store(base, matrix_ptr)?;
+        let n = self.builder.build_float_to_signed_int(exponent, i64_type, "exp_int")?;
+        self.builder.build_store(remaining, n)?;
 
-        // Condition
-        let cmp = self.builder.build_int_compare(inkwell::IntPredicate::SLT, next_i, total_size, "cmp");
-        self.builder.build_conditional_branch(cmp, loop_block, after_block)?;
+        let function = self.current_function();
+        let cond_block = self.context.append_basic_block(function, "pow_cond");
+        let body_block = self.context.append_basic_block(function, "pow_body");
+        let multiply_block = self.context.append_basic_block(function, "pow_multiply");
+        let shift_block = self.context.append_basic_block(function, "pow_shift");
+        let square_block = self.context.append_basic_block(function, "pow_square");
+        let end_block = self.context.append_basic_block(function, "pow_end");
 
-        self.builder.position_at_end(after_block);
+        // while (remaining > 0)
+        self.builder.build_unconditional_branch(cond_block)?;
+        self.builder.position_at_end(cond_block);
+        let n = self.builder.build_load(i64_type, remaining, "remaining")?.into_int_value();
+        let more = self.builder.build_int_compare(IntPredicate::SGT, n, zero, "pow_more")?;
+        self.builder.build_conditional_branch(more, body_block, end_block)?;
 
-        // Create Result Struct
-        let res_matrix_ptr = self.builder.build_malloc(self.matrix_type, "res_matrix")?;
+        // if (remaining & 1) result = result @ base
+        self.builder.position_at_end(body_block);
+        let low_bit = self.builder.build_and(n, i64_type.const_int(1, false), "low_bit")?;
+        let odd = self.builder.build_int_compare(IntPredicate::NE, low_bit, zero, "odd")?;
+        self.builder.build_conditional_branch(odd, multiply_block, shift_block)?;
 
-        // Set data
-        let res_data_field = self.builder.build_struct_gep(self.matrix_type, res_matrix_ptr, 0, "res_data_field")
-            .map_err(|_| anyhow!("GEP failed"))?;
-        self.builder.build_store(res_data_field, res_data_ptr)?;
+        self.builder.position_at_end(multiply_block);
+        let lhs = self.builder.build_load(ptr_type, result, "pow_result_val")?.into_pointer_value();
+        let rhs = self.builder.build_load(ptr_type, base, "pow_base_val")?.into_pointer_value();
+        let product = self.compile_matmul(&Op::Power, lhs, rhs)?;
+        self.builder.build_store(result, product)?;
+        self.builder.build_unconditional_branch(shift_block)?;
 
-        // Set rows
-        let res_rows_field = self.builder.build_struct_gep(self.matrix_type, res_matrix_ptr, 1, "res_rows_field")
-             .map_err(|_| anyhow!("GEP failed"))?;
-        self.builder.build_store(res_rows_field, rows)?;
+        // remaining >>= 1, then square the base only if it is still needed.
+        self.builder.position_at_end(shift_block);
+        let n = self.builder.build_load(i64_type, remaining, "remaining")?.into_int_value();
+        let half = self.builder.build_right_shift(n, i64_type.const_int(1, false), false, "half")?;
+        self.builder.build_store(remaining, half)?;
+        let more = self.builder.build_int_compare(IntPredicate::SGT, half, zero, "pow_more")?;
+        self.builder.build_conditional_branch(more, square_block, cond_block)?;
+
+        self.builder.position_at_end(square_block);
+        let current = self.builder.build_load(ptr_type, base, "pow_base_val")?.into_pointer_value();
+        let squared = self.compile_matmul(&Op::Power, current, current)?;
+        self.builder.build_store(base, squared)?;
+        self.builder.build_unconditional_branch(cond_block)?;
+
+        self.builder.position_at_end(end_block);
+        Ok(self.builder.build_load(ptr_type, result, "pow")?)
+    }
 
-        // Set cols
-        let res_cols_field = self.builder.build_struct_gep(self.matrix_type, res_matrix_ptr, 2, "res_cols_field")
-             .map_err(|_| anyhow!("GEP failed"))?;
-        self.builder.build_store(res_cols_field, cols)?;
+    /// Generates an `size x size` identity matrix.
+    fn build_identity(&mut self, size: IntValue<'ctx>) -> Result<PointerValue<'ctx>> {
+        let f64_type = self.context.f64_type();
+
+        let total_size = self.builder.build_int_mul(size, size, "total_size")?;
+        let data = self.builder.build_array_malloc(f64_type, total_size, "identity_data")?;
+
+        // Diagonal elements are the ones whose flat index is a multiple of `size + 1`.
+        let stride = self.builder.build_int_add(size, self.context.i64_type().const_int(1, false), "diag_stride")?;
+        self.build_counted_loop(total_size, "i", |cg, i| {
+            let offset = cg.builder.build_int_unsigned_rem(i, stride, "diag_offset")?;
+            let on_diagonal = cg.builder.build_int_compare(IntPredicate::EQ, offset, cg.context.i64_type().const_zero(), "on_diagonal")?;
+            let value = cg.builder.build_select(on_diagonal, f64_type.const_float(1.0), f64_type.const_zero(), "identity_val")?;
+
+            let elem_ptr = unsafe { cg.builder.build_gep(f64_type, data, &[i], "elem_ptr")? };
+            cg.builder.build_store(elem_ptr, value)?;
+            Ok(())
+        })?;
+
+        self.build_matrix_struct(data, size, size)
+    }
 
+    /// Generates `A'`: a `cols x rows` matrix with `res[j][i] = A[i][j]`.
+    fn compile_transpose(&mut self, matrix_ptr: PointerValue<'ctx>) -> Result<BasicValueEnum<'ctx>> {
+        let f64_type = self.context.f64_type();
+
+        let (rows, cols) = self.load_matrix_dims(matrix_ptr, "src")?;
+        let src_data = self.load_matrix_data(matrix_ptr, "src")?;
+
+        let total_size = self.builder.build_int_mul(rows, cols, "total_size")?;
+        let res_data = self.builder.build_array_malloc(f64_type, total_size, "res_data")?;
+
+        self.build_counted_loop(rows, "row", |cg, i| {
+            cg.build_counted_loop(cols, "col", |cg, j| {
+                let src_row_start = cg.builder.build_int_mul(i, cols, "src_row_start")?;
+                let src_index = cg.builder.build_int_add(src_row_start, j, "src_index")?;
+                let val = cg.load_element(src_data, src_index, "val")?;
+
+                let res_row_start = cg.builder.build_int_mul(j, rows, "res_row_start")?;
+                let res_index = cg.builder.build_int_add(res_row_start, i, "res_index")?;
+                let res_elem_ptr = unsafe { cg.builder.build_gep(f64_type, res_data, &[res_index], "res_elem_ptr")? };
+                cg.builder.build_store(res_elem_ptr, val)?;
+                Ok(())
+            })
+        })?;
+
+        let res_matrix_ptr = self.build_matrix_struct(res_data, cols, rows)?;
         Ok(res_matrix_ptr.into())
     }
+
+    /// Generates `A[i, j]` (or linear `A[i]`), with a runtime bounds check.
+    fn compile_index(&mut self, matrix_ptr: PointerValue<'ctx>, indices: &[Expr]) -> Result<BasicValueEnum<'ctx>> {
+        let elem_ptr = self.compile_element_ptr(matrix_ptr, indices)?;
+        Ok(self.builder.build_load(self.context.f64_type(), elem_ptr, "elem")?)
+    }
+
+    /// Computes the address of `A[i, j]` (or linear `A[i]`), with a runtime bounds check.
+    fn compile_element_ptr(&mut self, matrix_ptr: PointerValue<'ctx>, indices: &[Expr]) -> Result<PointerValue<'ctx>> {
+        let i64_type = self.context.i64_type();
+
+        let (rows, cols) = self.load_matrix_dims(matrix_ptr, "mat")?;
+        let data = self.load_matrix_data(matrix_ptr, "mat")?;
+
+        let index = match indices {
+            [i] => {
+                let i = self.compile_index_value(i, "i")?;
+                let len = self.builder.build_int_mul(rows, cols, "len")?;
+                // Unsigned comparison also rejects negative indices.
+                let in_bounds = self.builder.build_int_compare(IntPredicate::ULT, i, len, "in_bounds")?;
+                self.build_runtime_check(in_bounds, runtime::LINEAR_INDEX_OUT_OF_BOUNDS, "", [i, i64_type.const_zero(), rows, cols])?;
+                i
+            }
+            [i, j] => {
+                let i = self.compile_index_value(i, "i")?;
+                let j = self.compile_index_value(j, "j")?;
+                let row_ok = self.builder.build_int_compare(IntPredicate::ULT, i, rows, "row_ok")?;
+                let col_ok = self.builder.build_int_compare(IntPredicate::ULT, j, cols, "col_ok")?;
+                let in_bounds = self.builder.build_and(row_ok, col_ok, "in_bounds")?;
+                self.build_runtime_check(in_bounds, runtime::INDEX_OUT_OF_BOUNDS, "", [i, j, rows, cols])?;
+                let row_start = self.builder.build_int_mul(i, cols, "row_start")?;
+                self.builder.build_int_add(row_start, j, "index")?
+            }
+            _ => return Err(CompileError::TooManyIndices { found: indices.len(), span: self.span }),
+        };
+
+        Ok(unsafe { self.builder.build_gep(self.context.f64_type(), data, &[index], "elem_ptr")? })
+    }
+
+    /// Compiles a scalar index expression and converts it to an i64, raising a
+    /// runtime error unless it is a whole number.
+    fn compile_index_value(&mut self, expr: &Expr, name: &str) -> Result<IntValue<'ctx>> {
+        let i64_type = self.context.i64_type();
+        let val = self.compile_expr(expr)?;
+        if !val.is_float_value() {
+            return Err(self.type_error("Matrix indices must be scalars"));
+        }
+        let val = val.into_float_value();
+
+        let is_integer = self.build_is_i64(val, name)?;
+        let bits = self.builder.build_bit_cast(val, i64_type, "index_bits")?.into_int_value();
+        let zero = i64_type.const_zero();
+        // Point at the offending index rather than the whole subscript.
+        let outer = std::mem::replace(&mut self.span, expr.span);
+        let result = self.build_runtime_check(is_integer, runtime::INVALID_INDEX, "", [bits, zero, zero, zero]);
+        self.span = outer;
+        result?;
+
+        Ok(self.builder.build_float_to_signed_int(val, i64_type, name)?)
+    }
+
+    /// Checks that `x` is a whole number in the i64 range, the values for which
+    /// fptosi is neither poison nor truncating. NaN and infinities fail.
+    fn build_is_i64(&self, x: FloatValue<'ctx>, name: &str) -> Result<IntValue<'ctx>> {
+        let f64_type = self.context.f64_type();
+        let floor = self.build_float_intrinsic("llvm.floor", &[x], &format!("{}_floor", name))?;
+        let is_whole = self.builder.build_float_compare(FloatPredicate::OEQ, floor, x, &format!("{}_is_whole", name))?;
+        // -2^63 and 2^63 are exact doubles; the latter is just past i64::MAX.
+        let above_min = self.builder.build_float_compare(FloatPredicate::OGE, x, f64_type.const_float(i64::MIN as f64), &format!("{}_above_min", name))?;
+        let below_max = self.builder.build_float_compare(FloatPredicate::OLT, x, f64_type.const_float(i64::MAX as f64), &format!("{}_below_max", name))?;
+        let in_range = self.builder.build_and(above_min, below_max, &format!("{}_in_range", name))?;
+        Ok(self.builder.build_and(is_whole, in_range, &format!("{}_is_i64", name))?)
+    }
+
+    /// Generates `A[r0:r1:rs, c0:c1:cs]` (or linear `A[i0:i1:is]`) by copying the
+    /// selected elements into a fresh matrix.
+    fn compile_slice(&mut self, matrix_ptr: PointerValue<'ctx>, subscripts: &[Subscript]) -> Result<BasicValueEnum<'ctx>> {
+        let i64_type = self.context.i64_type();
+        let f64_type = self.context.f64_type();
+
+        let (rows, cols) = self.load_matrix_dims(matrix_ptr, "src")?;
+        let src_data = self.load_matrix_data(matrix_ptr, "src")?;
+
+        match subscripts {
+            [linear] => {
+                let len = self.builder.build_int_mul(rows, cols, "len")?;
+                let (start, count, step) = self.compile_slice_range(linear, len)?;
+                let res_data = self.builder.build_array_malloc(f64_type, count, "res_data")?;
+
+                self.build_counted_loop(count, "t", |cg, t| {
+                    let offset = cg.builder.build_int_mul(t, step, "offset")?;
+                    let src_index = cg.builder.build_int_add(start, offset, "src_index")?;
+                    let val = cg.load_element(src_data, src_index, "val")?;
+                    let res_elem_ptr = unsafe { cg.builder.build_gep(f64_type, res_data, &[t], "res_elem_ptr")? };
+                    cg.builder.build_store(res_elem_ptr, val)?;
+                    Ok(())
+                })?;
+
+                // Column vectors stay columns; everything else yields a row vector.
+                let one = i64_type.const_int(1, false);
+                let is_column = self.builder.build_int_compare(IntPredicate::EQ, cols, one, "is_column")?;
+                let res_rows = self.builder.build_select(is_column, count, one, "res_rows")?.into_int_value();
+                let res_cols = self.builder.build_select(is_column, one, count, "res_cols")?.into_int_value();
+
+                let res_matrix_ptr = self.build_matrix_struct(res_data, res_rows, res_cols)?;
+                Ok(res_matrix_ptr.into())
+            }
+            [row_range, col_range] => {
+                let (row_start, res_rows, row_step) = self.compile_slice_range(row_range, rows)?;
+                let (col_start, res_cols, col_step) = self.compile_slice_range(col_range, cols)?;
+
+                let total_size = self.builder.build_int_mul(res_rows, res_cols, "total_size")?;
+                let res_data = self.builder.build_array_malloc(f64_type, total_size, "res_data")?;
+
+                self.build_counted_loop(res_rows, "row", |cg, i| {
+                    cg.build_counted_loop(res_cols, "col", |cg, j| {
+                        // src[(row_start + i * row_step) * cols + (col_start + j * col_step)]
+                        let row_offset = cg.builder.build_int_mul(i, row_step, "row_offset")?;
+                        let src_row = cg.builder.build_int_add(row_start, row_offset, "src_row")?;
+                        let col_offset = cg.builder.build_int_mul(j, col_step, "col_offset")?;
+                        let src_col = cg.builder.build_int_add(col_start, col_offset, "src_col")?;
+                        let src_row_start = cg.builder.build_int_mul(src_row, cols, "src_row_start")?;
+                        let src_index = cg.builder.build_int_add(src_row_start, src_col, "src_index")?;
+                        let val = cg.load_element(src_data, src_index, "val")?;
+
+                        let res_row_start = cg.builder.build_int_mul(i, res_cols, "res_row_start")?;
+                        let res_index = cg.builder.build_int_add(res_row_start, j, "res_index")?;
+                        let res_elem_ptr = unsafe { cg.builder.build_gep(f64_type, res_data, &[res_index], "res_elem_ptr")? };
+                        cg.builder.build_store(res_elem_ptr, val)?;
+                        Ok(())
+                    })
+                })?;
+
+                let res_matrix_ptr = self.build_matrix_struct(res_data, res_rows, res_cols)?;
+                Ok(res_matrix_ptr.into())
+            }
+            _ => Err(CompileError::TooManyIndices { found: subscripts.len(), span: self.span }),
+        }
+    }
+
+    /// Resolves one slice dimension of length `len` to `(start, count, step)`,
+    /// checking at runtime that `0 <= start <= end <= len` and `step >= 1`.
+    fn compile_slice_range(&mut self, subscript: &Subscript, len: IntValue<'ctx>) -> Result<(IntValue<'ctx>, IntValue<'ctx>, IntValue<'ctx>)> {
+        let i64_type = self.context.i64_type();
+        let one = i64_type.const_int(1, false);
+
+        let (start, end, step) = match subscript {
+            Subscript::Point(index) => {
+                let index = self.compile_index_value(index, "slice_index")?;
+                let end = self.builder.build_int_add(index, one, "slice_end")?;
+                (index, end, one)
+            }
+            Subscript::Range { start, end, step } => {
+                let start = match start {
+                    Some(expr) => self.compile_index_value(expr, "slice_start")?,
+                    None => i64_type.const_zero(),
+                };
+                let end = match end {
+                    Some(expr) => self.compile_index_value(expr, "slice_end")?,
+                    None => len,
+                };
+                let step = match step {
+                    Some(expr) => self.compile_index_value(expr, "slice_step")?,
+                    None => one,
+                };
+                (start, end, step)
+            }
+        };
+
+        let start_ok = self.builder.build_int_compare(IntPredicate::SGE, start, i64_type.const_zero(), "start_ok")?;
+        let order_ok = self.builder.build_int_compare(IntPredicate::SLE, start, end, "order_ok")?;
+        let end_ok = self.builder.build_int_compare(IntPredicate::SLE, end, len, "end_ok")?;
+        let step_ok = self.builder.build_int_compare(IntPredicate::SGE, step, one, "step_ok")?;
+        let valid = self.builder.build_and(start_ok, order_ok, "valid")?;
+        let valid = self.builder.build_and(valid, end_ok, "valid")?;
+        let valid = self.builder.build_and(valid, step_ok, "valid")?;
+        self.build_runtime_check(valid, runtime::INVALID_SLICE, "", [start, end, step, len])?;
+
+        // count = ceil((end - start) / step), written so that a huge step can't overflow:
+        // (end - start - 1) / step + 1 when the range is non-empty, 0 otherwise.
+        let span = self.builder.build_int_sub(end, start, "span")?;
+        let last = self.builder.build_int_sub(span, one, "span_last")?;
+        let count = self.builder.build_int_signed_div(last, step, "count")?;
+        let count = self.builder.build_int_add(count, one, "count")?;
+        let non_empty = self.builder.build_int_compare(IntPredicate::SGT, span, i64_type.const_zero(), "non_empty")?;
+        let count = self.builder.build_select(non_empty, count, i64_type.const_zero(), "count")?.into_int_value();
+
+        Ok((start, count, step))
+    }
+
+    /// Returns the LLVM type used to pass values of the given type.
+    fn llvm_type(&self, ty: ValueType) -> BasicTypeEnum<'ctx> {
+        match ty {
+            ValueType::Scalar => self.context.f64_type().into(),
+            ValueType::Matrix => self.ptr_type().into(),
+            ValueType::Bool => self.context.bool_type().into(),
+        }
+    }
+
+    /// Returns the opaque pointer type used for matrices and their data buffers.
+    fn ptr_type(&self) -> PointerType<'ctx> {
+        self.context.ptr_type(AddressSpace::default())
+    }
+
+    /// Returns the function currently being generated.
+    fn current_function(&self) -> FunctionValue<'ctx> {
+        self.builder.get_insert_block().unwrap().get_parent().unwrap()
+    }
+
+    /// Loads the `rows` and `cols` fields of a matrix struct.
+    fn load_matrix_dims(&self, matrix_ptr: PointerValue<'ctx>, name: &str) -> Result<(IntValue<'ctx>, IntValue<'ctx>)> {
+        let i64_type = self.context.i64_type();
+
+        let rows_ptr = self.builder.build_struct_gep(self.matrix_type, matrix_ptr, 1, &format!("{}_rows_ptr", name))?;
+        let rows = self.builder.build_load(i64_type, rows_ptr, &format!("{}_rows", name))?.into_int_value();
+
+        let cols_ptr = self.builder.build_struct_gep(self.matrix_type, matrix_ptr, 2, &format!("{}_cols_ptr", name))?;
+        let cols = self.builder.build_load(i64_type, cols_ptr, &format!("{}_cols", name))?.into_int_value();
+
+        Ok((rows, cols))
+    }
+
+    /// Loads the `data` field of a matrix struct.
+    fn load_matrix_data(&self, matrix_ptr: PointerValue<'ctx>, name: &str) -> Result<PointerValue<'ctx>> {
+        let data_ptr_ptr = self.builder.build_struct_gep(self.matrix_type, matrix_ptr, 0, &format!("{}_data_ptr", name))?;
+        Ok(self.builder.build_load(self.ptr_type(), data_ptr_ptr, &format!("{}_data", name))?.into_pointer_value())
+    }
+
+    /// Loads `data[index]` from a flat f64 buffer.
+    fn load_element(&self, data: PointerValue<'ctx>, index: IntValue<'ctx>, name: &str) -> Result<FloatValue<'ctx>> {
+        let f64_type = self.context.f64_type();
+        let elem_ptr = unsafe { self.builder.build_gep(f64_type, data, &[index], "elem_ptr")? };
+        Ok(self.builder.build_load(f64_type, elem_ptr, name)?.into_float_value())
+    }
+
+    /// Allocates a matrix struct on the heap and fills in its fields.
+    fn build_matrix_struct(&self, data: PointerValue<'ctx>, rows: IntValue<'ctx>, cols: IntValue<'ctx>) -> Result<PointerValue<'ctx>> {
+        let matrix_ptr = self.builder.build_malloc(self.matrix_type, "res_matrix")?;
+
+        let data_field = self.builder.build_struct_gep(self.matrix_type, matrix_ptr, 0, "res_data_field")?;
+        self.builder.build_store(data_field, data)?;
+
+        let rows_field = self.builder.build_struct_gep(self.matrix_type, matrix_ptr, 1, "res_rows_field")?;
+        self.builder.build_store(rows_field, rows)?;
+
+        let cols_field = self.builder.build_struct_gep(self.matrix_type, matrix_ptr, 2, "res_cols_field")?;
+        self.builder.build_store(cols_field, cols)?;
+
+        Ok(matrix_ptr)
+    }
+
+    /// Emits `for (i = 0; i < count; i++) body(i)`, leaving the builder after the loop.
+    ///
+    /// `body` may create its own blocks (e.g. nested loops); the back edge is taken
+    /// from whichever block it finishes in.
+    fn build_counted_loop<F>(&mut self, count: IntValue<'ctx>, name: &str, body: F) -> Result<()>
+    where
+        F: FnOnce(&mut Self, IntValue<'ctx>) -> Result<()>,
+    {
+        let i64_type = self.context.i64_type();
+        let function = self.current_function();
+
+        let preheader = self.builder.get_insert_block().unwrap();
+        let header = self.context.append_basic_block(function, &format!("{}_header", name));
+        let body_block = self.context.append_basic_block(function, &format!("{}_body", name));
+        let exit = self.context.append_basic_block(function, &format!("{}_exit", name));
+
+        self.builder.build_unconditional_branch(header)?;
+        self.builder.position_at_end(header);
+
+        let index = self.builder.build_phi(i64_type, name)?;
+        index.add_incoming(&[(&i64_type.const_zero(), preheader)]);
+        let index_val = index.as_basic_value().into_int_value();
+
+        let cond = self.builder.build_int_compare(IntPredicate::SLT, index_val, count, &format!("{}_cond", name))?;
+        self.builder.build_conditional_branch(cond, body_block, exit)?;
+
+        self.builder.position_at_end(body_block);
+        body(self, index_val)?;
+
+        let latch = self.builder.get_insert_block().unwrap();
+        let next = self.builder.build_int_add(index_val, i64_type.const_int(1, false), &format!("{}_next", name))?;
+        self.builder.build_unconditional_branch(header)?;
+        index.add_incoming(&[(&next, latch)]);
+
+        self.builder.position_at_end(exit);
+        Ok(())
+    }
+
+    /// Calls the overloaded f64 variant of an LLVM intrinsic such as `llvm.pow`.
+    fn build_float_intrinsic(&self, name: &str, args: &[FloatValue<'ctx>], value_name: &str) -> Result<FloatValue<'ctx>> {
+        let f64_type = self.context.f64_type();
+        let intrinsic = Intrinsic::find(name).unwrap_or_else(|| panic!("unknown intrinsic {}", name));
+        let function = intrinsic.get_declaration(&self.module, &[f64_type.into()]).unwrap();
+
+        let args: Vec<BasicMetadataValueEnum> = args.iter().map(|&arg| arg.into()).collect();
+        let call = self.builder.build_call(function, &args, value_name)?;
+        Ok(call.try_as_basic_value().left().unwrap().into_float_value())
+    }
+
+    /// Emits a runtime check: if `ok` is false, reports the error through `runtime::RAISE_FN`,
+    /// at the span of the expression being compiled, and returns a zero value from the
+    /// current function.
+    fn build_runtime_check(&mut self, ok: IntValue<'ctx>, kind: u64, what: &str, details: [IntValue<'ctx>; 4]) -> Result<()> {
+        let i64_type = self.context.i64_type();
+        let function = self.current_function();
+
+        let fail_block = self.context.append_basic_block(function, "check_failed");
+        let ok_block = self.context.append_basic_block(function, "check_ok");
+        self.builder.build_conditional_branch(ok, ok_block, fail_block)?;
+
+        self.builder.position_at_end(fail_block);
+        let raise = self.module.get_function(runtime::RAISE_FN).unwrap();
+        let what = self.builder.build_global_string_ptr(what, "raise_what")?;
+        self.builder.build_call(
+            raise,
+            &[
+                i64_type.const_int(kind, false).into(),
+                what.as_pointer_value().into(),
+                details[0].into(),
+                details[1].into(),
+                details[2].into(),
+                details[3].into(),
+                i64_type.const_int(self.span.start as u64, false).into(),
+                i64_type.const_int(self.span.end as u64, false).into(),
+            ],
+            "",
+        )?;
+        self.build_zero_return()?;
+
+        self.builder.position_at_end(ok_block);
+        Ok(())
+    }
+
+    /// Returns a zero value from the current function unless `ok` is true.
+    fn build_return_unless(&mut self, ok: IntValue<'ctx>) -> Result<()> {
+        let function = self.current_function();
+
+        let fail_block = self.context.append_basic_block(function, "propagate_error");
+        let ok_block = self.context.append_basic_block(function, "no_error");
+        self.builder.build_conditional_branch(ok, ok_block, fail_block)?;
+
+        self.builder.position_at_end(fail_block);
+        self.build_zero_return()?;
+
+        self.builder.position_at_end(ok_block);
+        Ok(())
+    }
+
+    /// Returns the zero value of the current function's return type.
+    fn build_zero_return(&mut self) -> Result<()> {
+        match self.current_function().get_type().get_return_type() {
+            Some(ty) => self.builder.build_return(Some(&ty.const_zero()))?,
+            None => self.builder.build_return(None)?,
+        };
+        Ok(())
+    }
+}
+
+/// Returns whether a block contains a `return`, directly or in a nested block.
+fn contains_return(body: &[Stmt]) -> bool {
+    body.iter().any(|stmt| match &stmt.kind {
+        StmtKind::Return(_) => true,
+        StmtKind::If(_, then_body, else_body) => contains_return(then_body) || else_body.as_deref().is_some_and(contains_return),
+        StmtKind::While(_, body) | StmtKind::For(_, _, _, body) => contains_return(body),
+        _ => false,
+    })
+}
+
+/// Returns whether every path through a block ends in a `return`. Loops may run
+/// zero times, so only an `if` with an `else` returning on both sides counts, and a
+/// `while true` without a `break`, which can only be left by returning.
+fn always_returns(body: &[Stmt]) -> bool {
+    body.iter().any(|stmt| match &stmt.kind {
+        StmtKind::Return(_) => true,
+        StmtKind::If(_, then_body, Some(else_body)) => always_returns(then_body) && always_returns(else_body),
+        StmtKind::While(cond, body) => matches!(cond.kind, ExprKind::Bool(true)) && !contains_break(body),
+        _ => false,
+    })
+}
+
+/// Returns whether a loop body contains a `break` that leaves this loop rather
+/// than one nested inside it.
+fn contains_break(body: &[Stmt]) -> bool {
+    body.iter().any(|stmt| match &stmt.kind {
+        StmtKind::Break => true,
+        StmtKind::If(_, then_body, else_body) => contains_break(then_body) || else_body.as_deref().is_some_and(contains_break),
+        _ => false,
+    })
+}
+
+/// The value of a built-in constant. A variable of the same name shadows it, so
+/// scripts that already use `inf` or `nan` as names keep working.
+fn named_constant(name: &str) -> Option<f64> {
+    match name {
+        "inf" => Some(f64::INFINITY),
+        "nan" => Some(f64::NAN),
+        _ => None,
+    }
+}
+
+/// Describes a compiled value's type for error messages.
+pub(crate) fn type_name(ty: BasicTypeEnum<'_>) -> &'static str {
+    if ty.is_pointer_type() {
+        "matrix"
+    } else if ty.is_int_type() {
+        "boolean"
+    } else {
+        "scalar"
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
-enum FunctionReturnType {
+enum ValueType {
     Scalar,
     Matrix,
+    Bool,
+}
+
+impl ValueType {
+    /// The name of the type, as used in error messages (see `type_name`).
+    fn name(self) -> &'static str {
+        match self {
+            ValueType::Scalar => "scalar",
+            ValueType::Matrix => "matrix",
+            ValueType::Bool => "boolean",
+        }
+    }
+}
+
+impl From<Type> for ValueType {
+    fn from(ty: Type) -> Self {
+        match ty {
+            Type::Scalar => ValueType::Scalar,
+            Type::Matrix => ValueType::Matrix,
+            Type::Bool => ValueType::Bool,
+        }
+    }
+}
+
+/// The resolved parameter and return types of a function.
+#[derive(Debug, Clone, PartialEq)]
+struct Signature {
+    params: Vec<ValueType>,
+    ret: ValueType,
 }
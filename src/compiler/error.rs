@@ -0,0 +1,159 @@
+use crate::compiler::ast::Program;
+use crate::compiler::diagnostic::{self, Span};
+use std::fmt;
+use crate::compiler::lexer::Token;
+use thiserror::Error;
+
+pub use crate::compiler::runtime::RuntimeError;
+
+/// The result type used throughout the compiler.
+pub type Result<T, E = CompileError> = std::result::Result<T, E>;
+
+/// An error detected while lexing, parsing or compiling a script.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum CompileError {
+    /// The source contains text that is not a valid token.
+    #[error("Lexer error: found invalid token `{text}`")]
+    LexError { text: String, span: Span },
+    /// The parser found a token it did not expect, or ran out of input (`found` is `None`).
+    #[error("Expected {expected}, found {}", found.as_ref().map_or("end of input".to_string(), |t| t.to_string()))]
+    ParseError { expected: String, found: Option<Token>, span: Span },
+    /// An index or slice has more than two subscripts.
+    #[error("Expected 1 or 2 indices, found {found}")]
+    TooManyIndices { found: usize, span: Span },
+    /// A value has the wrong type for where it is used.
+    #[error("{message}")]
+    TypeError { message: String, span: Span },
+    /// A matrix literal is empty or has rows of different lengths.
+    #[error("{message}")]
+    ShapeError { message: String, span: Span },
+    /// A variable is used or assigned before it is declared.
+    #[error("Variable not found: {name}")]
+    UndefinedVariable { name: String, span: Span },
+    /// A call names a function that is not defined.
+    #[error("Undefined function: {name}")]
+    UndefinedFunction { name: String, span: Span },
+    /// A call passes the wrong number of arguments.
+    #[error("Function `{name}` takes {expected} arguments, found {found}")]
+    ArgumentCount { name: String, expected: usize, found: usize, span: Span },
+    /// Two functions share a name.
+    #[error("Function `{name}` is defined more than once")]
+    DuplicateFunction { name: String, span: Span },
+    /// A function is given the name of a builtin, so calls would never reach it.
+    #[error("Function `{name}` is a builtin and cannot be redefined")]
+    BuiltinFunction { name: String, span: Span },
+    /// A function returns a value on some paths but can reach its end on others.
+    #[error("Function `{name}` does not return a value on every path")]
+    MissingReturn { name: String, span: Span },
+    /// `break` or `continue` appears outside of any loop.
+    #[error("`{keyword}` outside of a loop")]
+    OutsideLoop { keyword: String, span: Span },
+    /// A `link` directive names a shared library that could not be loaded.
+    #[error("Could not load library `{path}`")]
+    LibraryNotFound { path: String, span: Span },
+    /// A declared function (an `extern fn`, or a runtime symbol such as `malloc`) was
+    /// found in no linked library or in the host process. The span is missing when the
+    /// module was handed to `Jit` without its program.
+    #[error("Symbol `{name}` could not be resolved")]
+    UndefinedSymbol { name: String, span: Option<Span> },
+    /// LLVM failed to build or run the module. These don't point into the source.
+    #[error("LLVM error: {message}")]
+    LlvmError { message: String },
+}
+
+impl CompileError {
+    /// Returns the location in the source the error refers to, if any.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CompileError::LexError { span, .. }
+            | CompileError::ParseError { span, .. }
+            | CompileError::TooManyIndices { span, .. }
+            | CompileError::TypeError { span, .. }
+            | CompileError::ShapeError { span, .. }
+            | CompileError::UndefinedVariable { span, .. }
+            | CompileError::UndefinedFunction { span, .. }
+            | CompileError::ArgumentCount { span, .. }
+            | CompileError::DuplicateFunction { span, .. }
+            | CompileError::BuiltinFunction { span, .. }
+            | CompileError::MissingReturn { span, .. }
+            | CompileError::OutsideLoop { span, .. }
+            | CompileError::LibraryNotFound { span, .. } => Some(*span),
+            CompileError::UndefinedSymbol { span, .. } => *span,
+            CompileError::LlvmError { .. } => None,
+        }
+    }
+
+    /// Renders the error with its file name, line, column and source snippet
+    /// (see `diagnostic::render`), or just the message if it has no location.
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        match self.span() {
+            Some(span) => diagnostic::render(&self.to_string(), span, file_name, source),
+            None => format!("error: {}\n", self),
+        }
+    }
+}
+
+impl From<inkwell::builder::BuilderError> for CompileError {
+    fn from(err: inkwell::builder::BuilderError) -> Self {
+        CompileError::LlvmError { message: err.to_string() }
+    }
+}
+
+/// The invalid tokens and syntax errors found by `Parser::parse_program`, in source order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseErrors {
+    /// Everything that parsed; statements and functions with errors are left out.
+    pub program: Program,
+    pub errors: Vec<CompileError>,
+}
+
+impl ParseErrors {
+    /// Renders every error, as `CompileError::render` does.
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        self.errors.iter().map(|err| err.render(file_name, source)).collect()
+    }
+}
+
+impl fmt::Display for ParseErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, err) in self.errors.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", err)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseErrors {}
+
+/// An error in a call from Rust into a compiled function, detected before it runs.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum CallError {
+    /// The program has no function with this name.
+    #[error("Function not found: {name}")]
+    UndefinedFunction { name: String },
+    /// The call passes the wrong number of arguments.
+    #[error("Function `{name}` takes {expected} arguments, found {found}")]
+    ArgumentCount { name: String, expected: usize, found: usize },
+    /// An argument does not have the type of the parameter it is passed to.
+    #[error("Argument {index} of `{name}` must be a {expected}, found a {found}")]
+    ArgumentType { name: String, index: usize, expected: &'static str, found: &'static str },
+    /// A matrix argument's data does not hold `rows * cols` elements.
+    #[error("Argument {index} of `{name}` is a {rows}x{cols} matrix, but has {len} elements")]
+    MatrixData { name: String, index: usize, rows: usize, cols: usize, len: usize },
+}
+
+/// Any error from compiling or running a script.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Parse(#[from] ParseErrors),
+    #[error(transparent)]
+    Compile(#[from] CompileError),
+    #[error(transparent)]
+    Runtime(#[from] RuntimeError),
+    #[error(transparent)]
+    Call(#[from] CallError),
+}
@@ -0,0 +1,268 @@
+use crate::compiler::diagnostic::{line_column, Span};
+use crate::compiler::error::Error;
+use crate::compiler::jit::Value;
+
+/// Options controlling how values are rendered for display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// Maximum number of digits after the decimal point (of the mantissa, in scientific notation).
+    pub precision: usize,
+    /// Matrices with more elements than this are shown with their middle rows and columns elided.
+    pub threshold: usize,
+    /// Number of rows and columns kept at each edge of an elided matrix.
+    pub edge_items: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self { precision: 6, threshold: 1000, edge_items: 3 }
+    }
+}
+
+/// Values at least this large (in magnitude) switch the whole matrix to scientific notation.
+const SCIENTIFIC_ABOVE: f64 = 1e8;
+
+/// Non-zero values smaller than this (in magnitude) switch the whole matrix to scientific notation.
+const SCIENTIFIC_BELOW: f64 = 1e-4;
+
+/// Renders a value for display. Matrices are rendered as a table with one line per
+/// row, right-aligned in columns:
+///
+/// ```text
+/// [[ 1.50 -2.00]
+///  [10.00  0.25]]
+/// ```
+pub fn format_value(value: &Value, options: &FormatOptions) -> String {
+    match value {
+        Value::Scalar(n) => {
+            let notation = Notation::choose([*n].into_iter(), options.precision);
+            notation.format(*n)
+        }
+        Value::Bool(b) => b.to_string(),
+        Value::Matrix { rows, cols, data } => format_matrix(*rows, *cols, data, options),
+    }
+}
+
+/// Renders a value as a JSON object tagged with its type; matrices carry their shape
+/// and their elements as an array of rows:
+///
+/// ```text
+/// {"type": "matrix", "rows": 2, "cols": 2, "data": [[1, 2], [3, 4]]}
+/// ```
+///
+/// JSON has no NaN or infinity, so those elements are written as `null`.
+pub fn value_to_json(value: &Value) -> String {
+    match value {
+        Value::Scalar(n) => format!("{{\"type\": \"scalar\", \"value\": {}}}", json_number(*n)),
+        Value::Bool(b) => format!("{{\"type\": \"bool\", \"value\": {}}}", b),
+        Value::Matrix { rows, cols, data } => {
+            let data: Vec<String> = matrix_rows(*rows, *cols, data)
+                .map(|row| {
+                    let row: Vec<String> = row.iter().map(|n| json_number(*n)).collect();
+                    format!("[{}]", row.join(", "))
+                })
+                .collect();
+            format!("{{\"type\": \"matrix\", \"rows\": {}, \"cols\": {}, \"data\": [{}]}}", rows, cols, data.join(", "))
+        }
+    }
+}
+
+/// Renders a value as CSV: one line per matrix row, or a single field for a scalar or boolean.
+pub fn value_to_csv(value: &Value) -> String {
+    match value {
+        Value::Scalar(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Matrix { rows, cols, data } => matrix_rows(*rows, *cols, data)
+            .map(|row| row.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(","))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/// Renders an error as a JSON object listing every error it contains, each with the
+/// stage that reported it and, when known, its location in `source`:
+///
+/// ```text
+/// {"errors": [{"kind": "compile", "message": "Variable not found: x",
+///              "span": {"start": 47, "end": 54, "line": 3, "column": 20}}]}
+/// ```
+pub fn error_to_json(err: &Error, source: &str) -> String {
+    let entries: Vec<(&str, String, Option<Span>)> = match err {
+        Error::Parse(errs) => errs.errors.iter().map(|e| ("parse", e.to_string(), e.span())).collect(),
+        Error::Compile(e) => vec![("compile", e.to_string(), e.span())],
+        Error::Runtime(e) => vec![("runtime", e.to_string(), Some(e.span()))],
+        Error::Call(e) => vec![("call", e.to_string(), None)],
+    };
+
+    let entries: Vec<String> = entries
+        .into_iter()
+        .map(|(kind, message, span)| {
+            let span = match span {
+                Some(span) => {
+                    let (line, column) = line_column(source, span.start);
+                    format!(
+                        "{{\"start\": {}, \"end\": {}, \"line\": {}, \"column\": {}}}",
+                        span.start, span.end, line, column
+                    )
+                }
+                None => "null".to_string(),
+            };
+            format!("{{\"kind\": \"{}\", \"message\": {}, \"span\": {}}}", kind, json_string(&message), span)
+        })
+        .collect();
+
+    format!("{{\"errors\": [{}]}}", entries.join(", "))
+}
+
+/// Renders an error that stops a script before it is compiled, such as a file that
+/// can't be read, in the same shape as `error_to_json`, with kind `"io"` and no span.
+pub fn io_error_to_json(message: &str) -> String {
+    format!("{{\"errors\": [{{\"kind\": \"io\", \"message\": {}, \"span\": null}}]}}", json_string(message))
+}
+
+/// Splits row-major matrix data into its rows.
+fn matrix_rows(rows: usize, cols: usize, data: &[f64]) -> impl Iterator<Item = &[f64]> {
+    (0..rows).map(move |i| &data[i * cols..(i + 1) * cols])
+}
+
+/// Writes a number as a JSON number, or `null` if it is NaN or infinite.
+fn json_number(n: f64) -> String {
+    if n.is_finite() {
+        n.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+/// Writes a string as a quoted JSON string.
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn format_matrix(rows: usize, cols: usize, data: &[f64], options: &FormatOptions) -> String {
+    if rows == 0 {
+        return "[]".to_string();
+    }
+
+    let elide = rows * cols > options.threshold;
+    let visible_rows = visible_indices(rows, elide, options.edge_items);
+    let visible_cols = visible_indices(cols, elide, options.edge_items);
+
+    // Every shown element uses the same notation and number of decimals, so decimal points line up.
+    let shown = visible_rows
+        .iter()
+        .flatten()
+        .flat_map(|&i| visible_cols.iter().flatten().map(move |&j| data[i * cols + j]));
+    let notation = Notation::choose(shown, options.precision);
+
+    // `None` marks an elided row or column.
+    let table: Vec<Option<Vec<String>>> = visible_rows
+        .iter()
+        .map(|row| {
+            row.map(|i| {
+                visible_cols
+                    .iter()
+                    .map(|col| match col {
+                        Some(j) => notation.format(data[i * cols + j]),
+                        None => "...".to_string(),
+                    })
+                    .collect()
+            })
+        })
+        .collect();
+
+    let mut widths = vec![0; visible_cols.len()];
+    for cells in table.iter().flatten() {
+        for (width, cell) in widths.iter_mut().zip(cells) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let lines: Vec<String> = table
+        .iter()
+        .map(|row| match row {
+            Some(cells) => {
+                let cells: Vec<String> = cells.iter().zip(&widths).map(|(cell, &width)| format!("{:>width$}", cell)).collect();
+                format!("[{}]", cells.join(" "))
+            }
+            None => "...".to_string(),
+        })
+        .collect();
+
+    format!("[{}]", lines.join("\n "))
+}
+
+/// Returns the indices shown along a dimension of length `len`: all of them, or when
+/// eliding, the first and last `edge_items` with `None` in place of the middle.
+fn visible_indices(len: usize, elide: bool, edge_items: usize) -> Vec<Option<usize>> {
+    if !elide || len <= 2 * edge_items {
+        return (0..len).map(Some).collect();
+    }
+    (0..edge_items)
+        .map(Some)
+        .chain(std::iter::once(None))
+        .chain((len - edge_items..len).map(Some))
+        .collect()
+}
+
+/// How a group of numbers is written: fixed-point or scientific, with a shared number of decimals.
+#[derive(Debug, Clone, Copy)]
+struct Notation {
+    scientific: bool,
+    decimals: usize,
+}
+
+impl Notation {
+    /// Picks the notation for a group of numbers: scientific if any of them is very large
+    /// or very small, and just enough decimals (at most `precision`) to show the most
+    /// precise of them.
+    fn choose(values: impl Iterator<Item = f64> + Clone, precision: usize) -> Self {
+        let magnitudes = values.clone().filter(|n| n.is_finite() && *n != 0.0).map(f64::abs);
+        let scientific = magnitudes.clone().any(|n| n >= SCIENTIFIC_ABOVE) || magnitudes.clone().any(|n| n < SCIENTIFIC_BELOW);
+
+        let full = Notation { scientific, decimals: precision };
+        let decimals = values
+            .filter(|n| n.is_finite())
+            .map(|n| {
+                let text = full.format(n);
+                let mantissa = text.split('e').next().unwrap();
+                let trailing_zeros = mantissa.len() - mantissa.trim_end_matches('0').len();
+                precision - trailing_zeros.min(precision)
+            })
+            .max()
+            .unwrap_or(0);
+
+        Notation { scientific, decimals }
+    }
+
+    /// Formats one number, e.g. `2.50` or `1.5e+10`.
+    fn format(&self, n: f64) -> String {
+        if !n.is_finite() {
+            return n.to_string().to_lowercase();
+        }
+        if !self.scientific {
+            return format!("{:.*}", self.decimals, n);
+        }
+
+        // Rust writes `1.5e10`; use a signed, two-digit exponent like `1.5e+10`.
+        let text = format!("{:.*e}", self.decimals, n);
+        let (mantissa, exponent) = text.split_once('e').unwrap();
+        let exponent: i32 = exponent.parse().unwrap();
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+    }
+}
@@ -0,0 +1,67 @@
+use crate::compiler::ast::Type;
+
+/// A Rust (or C) function that scripts can call by name, like a function defined in
+/// the script itself.
+///
+/// The function must be `extern "C"` and take and return the native form of each type:
+///
+/// | MatrixScript | Rust                              |
+/// |--------------|-----------------------------------|
+/// | `f64`        | `f64`                             |
+/// | `bool`       | `bool`                            |
+/// | `Matrix`     | `*const Matrix` / `*mut Matrix`   |
+///
+/// where `Matrix` is `runtime::Matrix`. Matrix arguments are borrowed from the script
+/// and must not be freed or kept; a returned matrix must be a new one from
+/// `runtime::alloc_matrix`.
+///
+/// ```no_run
+/// use matrix_script::compiler::ast::Type;
+/// use matrix_script::{Engine, HostFunction};
+///
+/// extern "C" fn clamp(x: f64, lo: f64, hi: f64) -> f64 {
+///     x.max(lo).min(hi)
+/// }
+///
+/// let mut engine = Engine::new();
+/// engine.register_function(unsafe {
+///     HostFunction::new("clamp", vec![Type::Scalar; 3], Type::Scalar, clamp as *const ())
+/// });
+/// let program = engine.compile("fn main() { return clamp(12, 0, 10); }")?;
+/// # Ok::<(), matrix_script::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostFunction {
+    pub(crate) name: String,
+    pub(crate) params: Vec<Type>,
+    pub(crate) ret: Type,
+    pub(crate) address: usize,
+}
+
+impl HostFunction {
+    /// Describes the function at `address` to the compiler.
+    ///
+    /// # Safety
+    ///
+    /// `address` must point to an `extern "C"` function whose parameters and return
+    /// value have the native types of `params` and `ret`, and which follows the
+    /// ownership rules above. Scripts call it without any further checks.
+    pub unsafe fn new(name: impl Into<String>, params: Vec<Type>, ret: Type, address: *const ()) -> Self {
+        Self { name: name.into(), params, ret, address: address as usize }
+    }
+
+    /// The name scripts call the function by.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The types of the function's parameters.
+    pub fn params(&self) -> &[Type] {
+        &self.params
+    }
+
+    /// The function's return type.
+    pub fn ret(&self) -> Type {
+        self.ret
+    }
+}
@@ -1,7 +1,90 @@
-use anyhow::{anyhow, Result};
-use inkwell::execution_engine::ExecutionEngine;
+use inkwell::execution_engine::{ExecutionEngine, JitFunction};
 use inkwell::module::Module;
+use inkwell::support::{load_library_permanently, load_visible_symbols, search_for_address_of_symbol};
+use inkwell::types::BasicTypeEnum;
 use inkwell::OptimizationLevel;
+use std::fmt;
+use std::path::Path;
+
+use crate::compiler::codegen::{type_name, CodeGen, ENTRY_PREFIX};
+use crate::compiler::error::{CallError, CompileError, Error, Result};
+use crate::compiler::host::HostFunction;
+use crate::compiler::runtime::{self, alloc_matrix, free_matrix, Matrix};
+
+/// A value returned from a JIT-compiled function.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(f64),
+    /// A matrix, with its elements in row-major order.
+    Matrix { rows: usize, cols: usize, data: Vec<f64> },
+    Bool(bool),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Scalar(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Matrix { rows, cols, data } => {
+                // Same syntax as a nested matrix literal, with one `[]` per row when there
+                // are no columns so a 3x0 matrix doesn't print like a 0x0 one.
+                write!(f, "[")?;
+                for (i, row) in (0..*rows).map(|i| &data[i * cols..(i + 1) * cols]).enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    let row: Vec<String> = row.iter().map(|n| n.to_string()).collect();
+                    write!(f, "[{}]", row.join(", "))?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// An argument passed from Rust to a JIT-compiled function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arg<'a> {
+    Scalar(f64),
+    /// A matrix with its elements in row-major order. The function works on a copy,
+    /// so `data` is never modified.
+    Matrix { rows: usize, cols: usize, data: &'a [f64] },
+    Bool(bool),
+}
+
+impl Arg<'_> {
+    /// The name of the argument's type, as used in error messages.
+    fn type_name(&self) -> &'static str {
+        match self {
+            Arg::Scalar(_) => "scalar",
+            Arg::Matrix { .. } => "matrix",
+            Arg::Bool(_) => "boolean",
+        }
+    }
+}
+
+impl From<f64> for Arg<'_> {
+    fn from(n: f64) -> Self {
+        Arg::Scalar(n)
+    }
+}
+
+impl From<bool> for Arg<'_> {
+    fn from(b: bool) -> Self {
+        Arg::Bool(b)
+    }
+}
+
+/// Lets the result of one call be passed to another.
+impl<'a> From<&'a Value> for Arg<'a> {
+    fn from(value: &'a Value) -> Self {
+        match value {
+            Value::Scalar(n) => Arg::Scalar(*n),
+            Value::Matrix { rows, cols, data } => Arg::Matrix { rows: *rows, cols: *cols, data },
+            Value::Bool(b) => Arg::Bool(*b),
+        }
+    }
+}
 
 /// The JIT engine.
 pub struct Jit<'ctx> {
@@ -9,24 +92,184 @@ pub struct Jit<'ctx> {
 }
 
 impl<'ctx> Jit<'ctx> {
-    /// Creates a new JIT engine for the given module.
+    /// Creates a new JIT engine for the given module. Extern functions are looked up
+    /// in the host process and the libraries loaded so far; use `from_codegen` to load
+    /// the program's `link`ed libraries first.
     pub fn new(module: &Module<'ctx>) -> Result<Self> {
+        Self::with_host_functions(module, &[])
+    }
+
+    /// Creates a new JIT engine for a module compiled with host functions registered,
+    /// resolving calls to them to their addresses.
+    ///
+    /// Every other function the module declares but does not define, such as `malloc`
+    /// or an `extern fn`, must be found in the host process or a loaded library, or
+    /// this returns `CompileError::UndefinedSymbol`.
+    pub fn with_host_functions(module: &Module<'ctx>, host_functions: &[HostFunction]) -> Result<Self> {
         let execution_engine = module
             .create_jit_execution_engine(OptimizationLevel::None)
-            .map_err(|e| anyhow!("Failed to create execution engine: {}", e))?;
+            .map_err(|e| CompileError::LlvmError { message: format!("Failed to create execution engine: {}", e) })?;
+
+        // Resolve the runtime support routines to their host implementations.
+        if let Some(raise) = module.get_function(runtime::RAISE_FN) {
+            execution_engine.add_global_mapping(&raise, runtime::matrix_script_raise as *const () as usize);
+        }
+        if let Some(pending) = module.get_function(runtime::PENDING_FN) {
+            execution_engine.add_global_mapping(&pending, runtime::matrix_script_error_pending as *const () as usize);
+        }
+        for host in host_functions {
+            if let Some(function) = module.get_function(&host.name) {
+                execution_engine.add_global_mapping(&function, host.address);
+            }
+        }
+
+        // Resolve the remaining declarations now: LLVM aborts the process on a symbol it can't find.
+        load_visible_symbols();
+        for function in module.get_functions() {
+            let name = function.get_name().to_string_lossy();
+            let mapped = name == runtime::RAISE_FN || name == runtime::PENDING_FN || host_functions.iter().any(|host| host.name == name);
+            if function.count_basic_blocks() > 0 || mapped || name.starts_with("llvm.") {
+                continue;
+            }
+            let address = search_for_address_of_symbol(&name).ok_or_else(|| CompileError::UndefinedSymbol { name: name.to_string(), span: None })?;
+            execution_engine.add_global_mapping(&function, address);
+        }
+
         Ok(Self { execution_engine })
     }
 
-    /// Runs the function with the given name.
-    /// Assumes the function takes no arguments and returns f64.
-    pub fn run(&self, function_name: &str) -> Result<f64> {
-        unsafe {
-            let func: inkwell::execution_engine::JitFunction<unsafe extern "C" fn() -> f64> =
-                self.execution_engine
-                .get_function(function_name)
-                .map_err(|_| anyhow!("Function {} not found in JIT", function_name))?;
+    /// Creates a new JIT engine for everything `codegen` compiled: its module, the host
+    /// functions registered on it, and the extern functions declared by the program,
+    /// which are looked up in the program's `link`ed libraries and the host process.
+    /// Unlike `new`, errors point at the `link` or `extern fn` at fault.
+    pub fn from_codegen(codegen: &CodeGen<'ctx>) -> Result<Self> {
+        // Symbols already in the process, like the C library's, need no `link`.
+        load_visible_symbols();
+        for link in codegen.links() {
+            load_library_permanently(Path::new(&link.path))
+                .map_err(|_| CompileError::LibraryNotFound { path: link.path.clone(), span: link.span })?;
+        }
+        for extern_fn in codegen.extern_functions() {
+            if search_for_address_of_symbol(&extern_fn.name).is_none() {
+                return Err(CompileError::UndefinedSymbol { name: extern_fn.name.clone(), span: Some(extern_fn.span) });
+            }
+        }
+
+        Self::with_host_functions(codegen.module(), codegen.host_functions())
+    }
+
+    /// Runs the function with the given name, which must take no arguments.
+    ///
+    /// Errors raised by the generated code (e.g. shape mismatches) are
+    /// returned as `Error::Runtime`.
+    pub fn run(&self, function_name: &str) -> Result<Value, Error> {
+        self.call(function_name, &[])
+    }
+
+    /// Calls the function with the given name. `args` are checked against the
+    /// function's compiled signature before it runs, and the result is read
+    /// according to its return type.
+    ///
+    /// Errors raised by the generated code (e.g. shape mismatches) are
+    /// returned as `Error::Runtime`.
+    pub fn call(&self, function_name: &str, args: &[Arg]) -> Result<Value, Error> {
+        let function = self
+            .execution_engine
+            .get_function_value(function_name)
+            .map_err(|_| CallError::UndefinedFunction { name: function_name.to_string() })?;
+
+        let param_types = function.get_type().get_param_types();
+        if param_types.len() != args.len() {
+            return Err(CallError::ArgumentCount { name: function_name.to_string(), expected: param_types.len(), found: args.len() }.into());
+        }
+        for (i, (arg, &param_type)) in args.iter().zip(&param_types).enumerate() {
+            if arg.type_name() != type_name(param_type) {
+                return Err(CallError::ArgumentType {
+                    name: function_name.to_string(),
+                    index: i + 1,
+                    expected: type_name(param_type),
+                    found: arg.type_name(),
+                }
+                .into());
+            }
+            if let Arg::Matrix { rows, cols, data } = *arg {
+                if data.len() != rows * cols {
+                    return Err(CallError::MatrixData { name: function_name.to_string(), index: i + 1, rows, cols, len: data.len() }.into());
+                }
+            }
+        }
+
+        // Each argument takes one 8-byte slot, read back by the function's entry point.
+        let mut matrices = Vec::new();
+        let slots: Vec<u64> = args
+            .iter()
+            .map(|arg| match *arg {
+                Arg::Scalar(n) => n.to_bits(),
+                Arg::Bool(b) => b as u64,
+                Arg::Matrix { rows, cols, data } => {
+                    let matrix = alloc_matrix(rows, cols, data);
+                    matrices.push(matrix);
+                    matrix as u64
+                }
+            })
+            .collect();
+
+        // Each call matches the signature `CodeGen` gives entry points of that return type.
+        let entry_name = format!("{}{}", ENTRY_PREFIX, function_name);
+        let result = match function.get_type().get_return_type() {
+            Some(BasicTypeEnum::FloatType(_)) => unsafe { self.call_entry::<f64>(function_name, &entry_name, &slots) }.map(Value::Scalar),
+            // Only the low bit of an `i1` return value is defined.
+            Some(BasicTypeEnum::IntType(_)) => unsafe { self.call_entry::<u8>(function_name, &entry_name, &slots) }.map(|b| Value::Bool(b & 1 != 0)),
+            Some(BasicTypeEnum::PointerType(_)) => unsafe { self.call_entry::<*mut Matrix>(function_name, &entry_name, &slots) }.map(|matrix| {
+                // A function may return one of its arguments, which is then freed with the result.
+                matrices.retain(|&arg| arg != matrix);
+                unsafe { take_matrix(matrix) }
+            }),
+            _ => Err(CompileError::LlvmError { message: format!("Function {} has an unsupported return type", function_name) }.into()),
+        };
+
+        for matrix in matrices {
+            unsafe { free_matrix(matrix) };
+        }
+        result
+    }
+
+    /// Calls the entry point of a function with its argument slots, then reports any
+    /// runtime error it raised.
+    ///
+    /// # Safety
+    ///
+    /// `T` must match the function's return type, and `slots` must hold its arguments.
+    unsafe fn call_entry<T>(&self, function_name: &str, entry_name: &str, slots: &[u64]) -> Result<T, Error> {
+        // Discard anything left over from an earlier run on this thread.
+        runtime::take_error();
+
+        let result = unsafe {
+            // Only functions defined by the program have an entry point.
+            let func: JitFunction<unsafe extern "C" fn(*const u64) -> T> = self
+                .execution_engine
+                .get_function(entry_name)
+                .map_err(|_| CallError::UndefinedFunction { name: function_name.to_string() })?;
+
+            func.call(slots.as_ptr())
+        };
 
-            Ok(func.call())
+        match runtime::take_error() {
+            Some(err) => Err(err.into()),
+            None => Ok(result),
         }
     }
 }
+
+/// Copies a matrix returned by generated code into a `Value` and frees the native buffers.
+///
+/// # Safety
+///
+/// `matrix` must point to a `malloc`ed matrix whose `data` buffer holds `rows * cols`
+/// elements, and must not be used afterwards.
+unsafe fn take_matrix(matrix: *mut Matrix) -> Value {
+    let (rows, cols, data) = unsafe { ((*matrix).rows as usize, (*matrix).cols as usize, (*matrix).as_slice().to_vec()) };
+    unsafe { free_matrix(matrix) };
+
+    Value::Matrix { rows, cols, data }
+}
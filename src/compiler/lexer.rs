@@ -1,4 +1,5 @@
 use logos::Logos;
+use std::fmt;
 
 /// Represents the tokens in the MatrixScript language.
 #[derive(Logos, Debug, PartialEq, Clone)]
@@ -13,6 +14,39 @@ pub enum Token {
     /// The `fn` keyword.
     #[token("fn")]
     Fn,
+    /// The `if` keyword.
+    #[token("if")]
+    If,
+    /// The `else` keyword.
+    #[token("else")]
+    Else,
+    /// The `while` keyword.
+    #[token("while")]
+    While,
+    /// The `for` keyword.
+    #[token("for")]
+    For,
+    /// The `in` keyword.
+    #[token("in")]
+    In,
+    /// The `break` keyword.
+    #[token("break")]
+    Break,
+    /// The `continue` keyword.
+    #[token("continue")]
+    Continue,
+    /// The `true` literal.
+    #[token("true")]
+    True,
+    /// The `false` literal.
+    #[token("false")]
+    False,
+    /// The `extern` keyword (for functions implemented in native code).
+    #[token("extern")]
+    Extern,
+    /// The `link` keyword (for shared libraries to load).
+    #[token("link")]
+    Link,
 
     /// The `+` operator.
     #[token("+")]
@@ -26,9 +60,67 @@ pub enum Token {
     /// The `/` operator.
     #[token("/")]
     Slash,
+    /// The `@` matrix multiplication operator.
+    #[token("@")]
+    At,
+    /// The `.*` element-wise multiplication operator.
+    #[token(".*")]
+    DotStar,
+    /// The `./` element-wise division operator.
+    #[token("./")]
+    DotSlash,
+    /// The `^` power operator.
+    #[token("^")]
+    Caret,
+    /// The `.^` element-wise power operator.
+    #[token(".^")]
+    DotCaret,
+    /// The `==` comparison operator.
+    #[token("==")]
+    EqEq,
+    /// The `!=` comparison operator.
+    #[token("!=")]
+    NotEq,
+    /// The `<` comparison operator.
+    #[token("<")]
+    Less,
+    /// The `<=` comparison operator.
+    #[token("<=")]
+    LessEq,
+    /// The `>` comparison operator.
+    #[token(">")]
+    Greater,
+    /// The `>=` comparison operator.
+    #[token(">=")]
+    GreaterEq,
+    /// The `&&` logical operator.
+    #[token("&&")]
+    AndAnd,
+    /// The `||` logical operator.
+    #[token("||")]
+    OrOr,
+    /// The `!` logical negation operator.
+    #[token("!")]
+    Bang,
+    /// The postfix transpose operator, written `'` or `ᵀ`.
+    #[token("'")]
+    #[token("ᵀ")]
+    Transpose,
     /// The `=` assignment operator.
     #[token("=")]
     Assign,
+    /// The `+=` compound assignment operator.
+    #[token("+=")]
+    PlusAssign,
+    /// The `-=` compound assignment operator.
+    #[token("-=")]
+    MinusAssign,
+    /// The `*=` compound assignment operator.
+    #[token("*=")]
+    StarAssign,
+    /// The `/=` compound assignment operator.
+    #[token("/=")]
+    SlashAssign,
     /// The `;` statement terminator.
     #[token(";")]
     SemiColon,
@@ -53,12 +145,101 @@ pub enum Token {
     /// The `,` symbol (for lists/matrices).
     #[token(",")]
     Comma,
+    /// The `:` symbol (for slice ranges).
+    #[token(":")]
+    Colon,
+    /// The `..` symbol (for `for` ranges).
+    #[token("..")]
+    DotDot,
+    /// The `->` symbol (for return types).
+    #[token("->")]
+    Arrow,
 
     /// An identifier.
     #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice().to_string())]
     Identifier(String),
 
-    /// A floating point number.
-    #[regex(r"[0-9]+(\.[0-9]+)?", |lex| lex.slice().parse().ok())]
+    /// A floating point number: `1.5`, `.5`, `1e-6` or `2.5E+3`. `inf` and `nan`
+    /// are identifiers, resolved by codegen unless a variable has that name.
+    /// Digits may be grouped with `_`. There is no trailing-dot form (`1.`), so
+    /// ranges like `0..n` still lex as `0`, `..`, `n`.
+    #[regex(r"[0-9][0-9_]*\.[0-9][0-9_]*([eE][+-]?[0-9][0-9_]*)?", parse_float)]
+    #[regex(r"\.[0-9][0-9_]*([eE][+-]?[0-9][0-9_]*)?", parse_float)]
+    #[regex(r"[0-9][0-9_]*[eE][+-]?[0-9][0-9_]*", parse_float)]
     Number(f64),
+
+    /// An integer literal: `42` or `1_000_000`. Literals that don't fit in an
+    /// `i64` are rejected.
+    #[regex(r"[0-9][0-9_]*", |lex| lex.slice().replace('_', "").parse().ok())]
+    Integer(i64),
+
+    /// A string literal in double quotes, such as a library path. There are no escapes.
+    #[regex(r#""[^"\n]*""#, |lex| { let text = lex.slice(); text[1..text.len() - 1].to_string() })]
+    Str(String),
+}
+
+/// Parses a float literal, ignoring `_` digit separators.
+fn parse_float(lex: &mut logos::Lexer<Token>) -> Option<f64> {
+    lex.slice().replace('_', "").parse().ok()
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Token::Let => "let",
+            Token::Return => "return",
+            Token::Fn => "fn",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::While => "while",
+            Token::For => "for",
+            Token::In => "in",
+            Token::Break => "break",
+            Token::Continue => "continue",
+            Token::True => "true",
+            Token::False => "false",
+            Token::Extern => "extern",
+            Token::Link => "link",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::At => "@",
+            Token::DotStar => ".*",
+            Token::DotSlash => "./",
+            Token::Caret => "^",
+            Token::DotCaret => ".^",
+            Token::EqEq => "==",
+            Token::NotEq => "!=",
+            Token::Less => "<",
+            Token::LessEq => "<=",
+            Token::Greater => ">",
+            Token::GreaterEq => ">=",
+            Token::AndAnd => "&&",
+            Token::OrOr => "||",
+            Token::Bang => "!",
+            Token::Transpose => "'",
+            Token::Assign => "=",
+            Token::PlusAssign => "+=",
+            Token::MinusAssign => "-=",
+            Token::StarAssign => "*=",
+            Token::SlashAssign => "/=",
+            Token::SemiColon => ";",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::Comma => ",",
+            Token::Colon => ":",
+            Token::DotDot => "..",
+            Token::Arrow => "->",
+            Token::Identifier(name) => return write!(f, "identifier `{}`", name),
+            Token::Number(n) => return write!(f, "number `{}`", n),
+            Token::Integer(n) => return write!(f, "integer `{}`", n),
+            Token::Str(text) => return write!(f, "string \"{}\"", text),
+        };
+        write!(f, "`{}`", text)
+    }
 }
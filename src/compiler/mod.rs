@@ -1,5 +1,10 @@
 pub mod ast;
+pub mod diagnostic;
+pub mod error;
 pub mod lexer;
 pub mod parser;
 pub mod codegen;
 pub mod jit;
+pub mod format;
+pub mod host;
+pub mod runtime;
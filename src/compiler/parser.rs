@@ -1,41 +1,84 @@
-use crate::compiler::ast::{Expr, Function, Op, Program, Stmt};
+use crate::compiler::ast::{Expr, ExprKind, ExternFunction, Function, Link, Op, Param, Program, Stmt, StmtKind, Subscript, Type, UnaryOp};
+use crate::compiler::diagnostic::Span;
+use crate::compiler::error::{CompileError, ParseErrors, Result};
 use crate::compiler::lexer::Token;
-use anyhow::{anyhow, bail, Result};
 use logos::Logos;
 
-/// The parser struct which holds the tokens and current position.
+/// Functions the parser turns into their own expressions, such as `transpose(A)`.
+/// Scripts can't define or declare functions with these names.
+const BUILTIN_FUNCTIONS: [&str; 1] = ["transpose"];
+
+/// The parser struct which holds the tokens, their source spans and current position.
 pub struct Parser {
-    tokens: Vec<Token>,
+    tokens: Vec<(Token, Span)>,
     pos: usize,
+    /// An empty span at the end of the input, reported when the input ends too early.
+    eof: Span,
+    /// Lexer and syntax errors recovered from so far.
+    errors: Vec<CompileError>,
 }
 
 impl Parser {
     /// Creates a new Parser from the source code.
-    pub fn new(input: &str) -> Result<Self> {
+    ///
+    /// Invalid tokens are left out of the token stream and reported by `parse_program`
+    /// along with the syntax errors.
+    pub fn new(input: &str) -> Self {
         let mut tokens = Vec::new();
-        for (token, _span) in Token::lexer(input).spanned() {
+        let mut errors = Vec::new();
+        for (token, span) in Token::lexer(input).spanned() {
             match token {
-                Ok(t) => tokens.push(t),
-                Err(_) => bail!("Lexer error: found invalid token"),
+                Ok(t) => tokens.push((t, span.into())),
+                Err(_) => {
+                    let text = input[span.clone()].to_string();
+                    errors.push(CompileError::LexError { text, span: span.into() });
+                }
             }
         }
-        Ok(Self { tokens, pos: 0 })
+        Self { tokens, pos: 0, eof: Span::new(input.len(), input.len()), errors }
     }
 
     /// Peeks at the current token.
     fn peek(&self) -> Option<&Token> {
-        self.tokens.get(self.pos)
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    /// Peeks at the token after the current one.
+    fn peek_next(&self) -> Option<&Token> {
+        self.tokens.get(self.pos + 1).map(|(token, _)| token)
     }
 
     /// Advances to the next token and returns the current one.
     fn advance(&mut self) -> Option<&Token> {
-        let token = self.tokens.get(self.pos);
+        let token = self.tokens.get(self.pos).map(|(token, _)| token);
         if token.is_some() {
             self.pos += 1;
         }
         token
     }
 
+    /// Returns the span of the current token, or the end of the input.
+    fn current_span(&self) -> Span {
+        self.tokens.get(self.pos).map_or(self.eof, |(_, span)| *span)
+    }
+
+    /// Returns the span from `start` to the end of the last consumed token.
+    fn span_from(&self, start: Span) -> Span {
+        match self.pos.checked_sub(1).and_then(|i| self.tokens.get(i)) {
+            Some((_, last)) => start.to(*last),
+            None => start,
+        }
+    }
+
+    /// Builds a parse error reporting the current token where `expected` should be.
+    fn unexpected(&self, expected: impl Into<String>) -> CompileError {
+        CompileError::ParseError {
+            expected: expected.into(),
+            found: self.peek().cloned(),
+            span: self.current_span(),
+        }
+    }
+
     /// Checks if the current token matches the expected token and advances if so.
     fn match_token(&mut self, expected: Token) -> bool {
         if let Some(token) = self.peek() {
@@ -52,163 +95,608 @@ impl Parser {
         if self.match_token(expected.clone()) {
             Ok(())
         } else {
-            bail!("Expected {:?}, found {:?}", expected, self.peek())
+            Err(self.unexpected(expected.to_string()))
         }
     }
 
+    /// Expects an identifier and returns its name; `what` describes it in the error.
+    fn expect_identifier(&mut self, what: &str) -> Result<String> {
+        match self.peek() {
+            Some(Token::Identifier(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(self.unexpected(what)),
+        }
+    }
+
+    /// Expects the name of a function being defined or declared, which can't be a builtin.
+    fn expect_function_name(&mut self) -> Result<String> {
+        let span = self.current_span();
+        let name = self.expect_identifier("function name")?;
+        if BUILTIN_FUNCTIONS.contains(&name.as_str()) {
+            return Err(CompileError::BuiltinFunction { name, span });
+        }
+        Ok(name)
+    }
+
     /// Parses the entire program.
-    pub fn parse_program(&mut self) -> Result<Program> {
-        let mut functions = Vec::new();
-        while self.peek().is_some() {
-            functions.push(self.parse_function()?);
+    ///
+    /// Parsing carries on past invalid tokens and syntax errors, so every error in
+    /// the file is reported at once. On failure the returned `ParseErrors` holds all
+    /// of them in source order, along with the functions and statements that did parse.
+    pub fn parse_program(&mut self) -> Result<Program, ParseErrors> {
+        let mut program = Program { links: Vec::new(), externs: Vec::new(), functions: Vec::new() };
+        while let Some(token) = self.peek() {
+            let item = match token {
+                Token::Link => self.parse_link().map(|link| program.links.push(link)),
+                Token::Extern => self.parse_extern_function().map(|extern_fn| program.externs.push(extern_fn)),
+                _ => self.parse_function().map(|function| program.functions.push(function)),
+            };
+            if let Err(err) = item {
+                self.errors.push(err);
+                self.skip_to_next_function();
+            }
+        }
+
+        if self.errors.is_empty() {
+            Ok(program)
+        } else {
+            self.errors.sort_by_key(|err| err.span().map(|span| span.start));
+            Err(ParseErrors { program, errors: std::mem::take(&mut self.errors) })
+        }
+    }
+
+    /// Skips tokens up to the next `fn`, `extern` or `link`, after an error outside
+    /// any function body.
+    fn skip_to_next_function(&mut self) {
+        while !matches!(self.peek(), Some(Token::Fn | Token::Extern | Token::Link) | None) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skips the rest of a statement after a syntax error: up to and including
+    /// the next `;`, or up to the `}` closing the current block or the next `fn`.
+    /// Blocks opened along the way are skipped whole.
+    ///
+    /// A `;` inside `[...]` separates matrix rows rather than statements, so brackets
+    /// are counted from `from`, the first token of the statement.
+    fn synchronize(&mut self, from: usize) {
+        let mut depth = 0;
+        let mut brackets = self.tokens[from..self.pos].iter().fold(0usize, |open, (token, _)| match token {
+            Token::LBracket => open + 1,
+            Token::RBracket => open.saturating_sub(1),
+            _ => open,
+        });
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Fn | Token::Extern | Token::Link => return,
+                Token::LBracket => brackets += 1,
+                Token::RBracket => brackets = brackets.saturating_sub(1),
+                Token::SemiColon if depth == 0 && brackets == 0 => {
+                    self.pos += 1;
+                    return;
+                }
+                Token::RBrace if depth == 0 => return,
+                Token::RBrace => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.pos += 1;
+                        return;
+                    }
+                }
+                Token::LBrace => depth += 1,
+                _ => {}
+            }
+            self.pos += 1;
         }
-        Ok(Program { functions })
     }
 
     /// Parses a function definition.
     fn parse_function(&mut self) -> Result<Function> {
+        let start = self.current_span();
         self.expect(Token::Fn)?;
-        let name = match self.advance() {
-            Some(Token::Identifier(name)) => name.clone(),
-            t => bail!("Expected function name, found {:?}", t),
-        };
+        let name = self.expect_function_name()?;
+        self.expect(Token::LParen)?;
+        let mut params = Vec::new();
+        while !matches!(self.peek(), Some(Token::RParen)) {
+            params.push(self.parse_param()?);
+            if !self.match_token(Token::Comma) {
+                break;
+            }
+        }
+        self.expect(Token::RParen)?;
+        let span = self.span_from(start);
+        let body = self.parse_block()?;
+
+        Ok(Function { name, params, body, span })
+    }
+
+    /// Parses a native function declaration: `extern fn name(x: f64, A: Matrix) -> f64;`.
+    fn parse_extern_function(&mut self) -> Result<ExternFunction> {
+        let start = self.current_span();
+        self.expect(Token::Extern)?;
+        self.expect(Token::Fn)?;
+        let name = self.expect_function_name()?;
         self.expect(Token::LParen)?;
-        self.expect(Token::RParen)?; // Arguments not supported yet
+        let mut params = Vec::new();
+        while !matches!(self.peek(), Some(Token::RParen)) {
+            let param = self.parse_param()?;
+            // There is no body to infer types from.
+            if param.ty.is_none() {
+                return Err(CompileError::TypeError {
+                    message: format!("Parameter `{}` of extern function `{}` must have a type", param.name, name),
+                    span: param.span,
+                });
+            }
+            params.push(param);
+            if !self.match_token(Token::Comma) {
+                break;
+            }
+        }
+        self.expect(Token::RParen)?;
+        let ret = if self.match_token(Token::Arrow) { self.parse_type("return type `f64`, `Matrix` or `bool`")? } else { Type::Scalar };
+        self.expect(Token::SemiColon)?;
+
+        Ok(ExternFunction { name, params, ret, span: self.span_from(start) })
+    }
+
+    /// Parses a `link "libfoo.so";` directive.
+    fn parse_link(&mut self) -> Result<Link> {
+        let start = self.current_span();
+        self.expect(Token::Link)?;
+        let path = match self.peek() {
+            Some(Token::Str(path)) => path.clone(),
+            _ => return Err(self.unexpected("library path")),
+        };
+        self.advance();
+        self.expect(Token::SemiColon)?;
+
+        Ok(Link { path, span: self.span_from(start) })
+    }
+
+    /// Parses a `{ ... }` block of statements.
+    fn parse_block(&mut self) -> Result<Vec<Stmt>> {
         self.expect(Token::LBrace)?;
 
         let mut body = Vec::new();
         while let Some(token) = self.peek() {
-            if *token == Token::RBrace {
+            // A `fn` here means the block was never closed; report that below.
+            if matches!(token, Token::RBrace | Token::Fn | Token::Extern | Token::Link) {
                 break;
             }
-            body.push(self.parse_stmt()?);
+            let start = self.pos;
+            match self.parse_stmt() {
+                Ok(stmt) => body.push(stmt),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.synchronize(start);
+                }
+            }
         }
         self.expect(Token::RBrace)?;
 
-        Ok(Function { name, body })
+        Ok(body)
+    }
+
+    /// Parses a function parameter: `x` or `x: f64` / `A: Matrix`.
+    fn parse_param(&mut self) -> Result<Param> {
+        let start = self.current_span();
+        let name = self.expect_identifier("parameter name")?;
+
+        let ty = if self.match_token(Token::Colon) { Some(self.parse_type("parameter type `f64`, `Matrix` or `bool`")?) } else { None };
+
+        Ok(Param { name, ty, span: self.span_from(start) })
+    }
+
+    /// Parses a type name; `expected` describes it in the error.
+    fn parse_type(&mut self, expected: &str) -> Result<Type> {
+        let ty = match self.peek() {
+            Some(Token::Identifier(ty)) if ty == "f64" => Type::Scalar,
+            Some(Token::Identifier(ty)) if ty == "Matrix" => Type::Matrix,
+            Some(Token::Identifier(ty)) if ty == "bool" => Type::Bool,
+            _ => return Err(self.unexpected(expected)),
+        };
+        self.advance();
+        Ok(ty)
     }
 
     /// Parses a statement.
     fn parse_stmt(&mut self) -> Result<Stmt> {
-        match self.peek() {
+        let start = self.current_span();
+        let kind = match self.peek() {
             Some(Token::Let) => {
                 self.advance();
-                let name = match self.advance() {
-                    Some(Token::Identifier(name)) => name.clone(),
-                    t => bail!("Expected variable name, found {:?}", t),
-                };
+                let name = self.expect_identifier("variable name")?;
                 self.expect(Token::Assign)?;
                 let expr = self.parse_expr()?;
                 self.expect(Token::SemiColon)?;
-                Ok(Stmt::Let(name, expr))
+                StmtKind::Let(name, expr)
             }
             Some(Token::Return) => {
                 self.advance();
                 let expr = self.parse_expr()?;
                 self.expect(Token::SemiColon)?;
-                Ok(Stmt::Return(expr))
+                StmtKind::Return(expr)
             }
-            t => bail!("Expected statement, found {:?}", t),
-        }
+            Some(Token::If) => return self.parse_if_stmt(),
+            Some(Token::While) => {
+                self.advance();
+                let cond = self.parse_expr()?;
+                let body = self.parse_block()?;
+                StmtKind::While(cond, body)
+            }
+            Some(Token::For) => {
+                self.advance();
+                let var = self.expect_identifier("loop variable name")?;
+                self.expect(Token::In)?;
+                let start = self.parse_expr()?;
+                self.expect(Token::DotDot)?;
+                let end = self.parse_expr()?;
+                let body = self.parse_block()?;
+                StmtKind::For(var, start, end, body)
+            }
+            Some(Token::Break) => {
+                self.advance();
+                self.expect(Token::SemiColon)?;
+                StmtKind::Break
+            }
+            Some(Token::Continue) => {
+                self.advance();
+                self.expect(Token::SemiColon)?;
+                StmtKind::Continue
+            }
+            Some(Token::Identifier(_)) if self.peek_next() == Some(&Token::LParen) => {
+                let expr = self.parse_expr()?;
+                self.expect(Token::SemiColon)?;
+                StmtKind::Expr(expr)
+            }
+            Some(Token::Identifier(_)) => self.parse_assignment()?,
+            _ => return Err(self.unexpected("statement")),
+        };
+        Ok(Stmt::new(kind, self.span_from(start)))
     }
 
-    /// Parses an expression (handles + and -).
-    fn parse_expr(&mut self) -> Result<Expr> {
-        let mut left = self.parse_term()?;
+    /// Parses `if cond { ... }` with optional `else if` / `else` parts.
+    fn parse_if_stmt(&mut self) -> Result<Stmt> {
+        let start = self.current_span();
+        self.expect(Token::If)?;
+        let cond = self.parse_expr()?;
+        let then_body = self.parse_block()?;
 
-        while let Some(token) = self.peek() {
-            match token {
-                Token::Plus => {
-                    self.advance();
-                    let right = self.parse_term()?;
-                    left = Expr::BinaryOp(Box::new(left), Op::Add, Box::new(right));
-                }
-                Token::Minus => {
-                    self.advance();
-                    let right = self.parse_term()?;
-                    left = Expr::BinaryOp(Box::new(left), Op::Subtract, Box::new(right));
-                }
-                _ => break,
+        let else_body = if self.match_token(Token::Else) {
+            if matches!(self.peek(), Some(Token::If)) {
+                Some(vec![self.parse_if_stmt()?])
+            } else {
+                Some(self.parse_block()?)
             }
+        } else {
+            None
+        };
+
+        Ok(Stmt::new(StmtKind::If(cond, then_body, else_body), self.span_from(start)))
+    }
+
+    /// Parses a conditional expression: `if cond { a } else { b }`, where `else if` may be chained.
+    /// The `if` keyword, at `start`, has already been consumed.
+    fn parse_if_expr(&mut self, start: Span) -> Result<Expr> {
+        let cond = self.parse_expr()?;
+        self.expect(Token::LBrace)?;
+        let then_expr = self.parse_expr()?;
+        self.expect(Token::RBrace)?;
+
+        self.expect(Token::Else)?;
+        let else_start = self.current_span();
+        let else_expr = if self.match_token(Token::If) {
+            self.parse_if_expr(else_start)?
+        } else {
+            self.expect(Token::LBrace)?;
+            let else_expr = self.parse_expr()?;
+            self.expect(Token::RBrace)?;
+            else_expr
+        };
+
+        let kind = ExprKind::If(Box::new(cond), Box::new(then_expr), Box::new(else_expr));
+        Ok(Expr::new(kind, self.span_from(start)))
+    }
+
+    /// Parses an assignment: `x = e;`, `x += e;` or `A[i, j] = e;` (and the other compound forms).
+    fn parse_assignment(&mut self) -> Result<StmtKind> {
+        let name = self.expect_identifier("variable name")?;
+
+        let indices = if self.match_token(Token::LBracket) {
+            let mut indices = vec![self.parse_expr()?];
+            while self.match_token(Token::Comma) {
+                indices.push(self.parse_expr()?);
+            }
+            if indices.len() > 2 {
+                return Err(CompileError::TooManyIndices { found: indices.len(), span: self.current_span() });
+            }
+            self.expect(Token::RBracket)?;
+            Some(indices)
+        } else {
+            None
+        };
+
+        let op = match self.peek() {
+            Some(Token::Assign) => None,
+            Some(Token::PlusAssign) => Some(Op::Add),
+            Some(Token::MinusAssign) => Some(Op::Subtract),
+            Some(Token::StarAssign) => Some(Op::Multiply),
+            Some(Token::SlashAssign) => Some(Op::Divide),
+            _ => return Err(self.unexpected("assignment operator")),
+        };
+        self.advance();
+
+        let expr = self.parse_expr()?;
+        self.expect(Token::SemiColon)?;
+
+        match indices {
+            Some(indices) => Ok(StmtKind::IndexAssign(name, indices, op, expr)),
+            None => Ok(StmtKind::Assign(name, op, expr)),
         }
-        Ok(left)
     }
 
-    /// Parses a term (handles * and /).
-    fn parse_term(&mut self) -> Result<Expr> {
+    /// Builds `left op right`, spanning both operands.
+    fn binary(left: Expr, op: Op, right: Expr) -> Expr {
+        let span = left.span.to(right.span);
+        Expr::new(ExprKind::BinaryOp(Box::new(left), op, Box::new(right)), span)
+    }
+
+    /// Parses an expression.
+    fn parse_expr(&mut self) -> Result<Expr> {
+        self.parse_binary(0)
+    }
+
+    /// Parses a chain of binary operators by precedence climbing, consuming only
+    /// operators that bind at least as tightly as `min_precedence`.
+    ///
+    /// Precedence and associativity come from `binary_operator`.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr> {
         let mut left = self.parse_factor()?;
 
-        while let Some(token) = self.peek() {
-            match token {
-                Token::Star => {
-                    self.advance();
-                    let right = self.parse_factor()?;
-                    left = Expr::BinaryOp(Box::new(left), Op::Multiply, Box::new(right));
-                }
-                Token::Slash => {
-                    self.advance();
-                    let right = self.parse_factor()?;
-                    left = Expr::BinaryOp(Box::new(left), Op::Divide, Box::new(right));
-                }
-                _ => break,
+        while let Some((op, precedence, associativity)) = self.peek().and_then(binary_operator) {
+            if precedence < min_precedence {
+                break;
             }
+            self.advance();
+
+            // A left-associative operator stops the right operand at its own level,
+            // so `a - b - c` groups as `(a - b) - c`; a right-associative one does not.
+            let next_min = match associativity {
+                Associativity::Left => precedence + 1,
+                Associativity::Right => precedence,
+            };
+            let right = self.parse_binary(next_min)?;
+            left = Self::binary(left, op, right);
         }
         Ok(left)
     }
 
-    /// Parses a factor (numbers, identifiers, parens, matrices).
+    /// Parses a factor: prefix operators, then a primary expression followed by postfix operators.
+    ///
+    /// A prefix operator's operand extends over every binary operator that binds tighter
+    /// than it (see `prefix_operator`), so `-a ^ 2` is `-(a ^ 2)`.
     fn parse_factor(&mut self) -> Result<Expr> {
-        match self.advance() {
-            Some(Token::Number(n)) => Ok(Expr::Number(*n)),
-            Some(Token::Identifier(name)) => Ok(Expr::Identifier(name.clone())),
+        let start = self.current_span();
+        if let Some((op, precedence)) = self.peek().and_then(prefix_operator) {
+            self.advance();
+            let expr = self.parse_binary(precedence)?;
+            return Ok(Expr::new(ExprKind::UnaryOp(op, Box::new(expr)), self.span_from(start)));
+        }
+
+        let mut expr = self.parse_primary()?;
+        while let Some(op) = self.peek().and_then(postfix_operator) {
+            self.advance();
+            let kind = match op {
+                Postfix::Transpose => ExprKind::Transpose(Box::new(expr)),
+                Postfix::Subscript => self.parse_subscripts(expr)?,
+            };
+            expr = Expr::new(kind, self.span_from(start));
+        }
+        Ok(expr)
+    }
+
+    /// Parses the subscripts of `expr[...]` after the opening `[`.
+    fn parse_subscripts(&mut self, expr: Expr) -> Result<ExprKind> {
+        let mut subscripts = vec![self.parse_subscript()?];
+        while self.match_token(Token::Comma) {
+            subscripts.push(self.parse_subscript()?);
+        }
+        if subscripts.len() > 2 {
+            return Err(CompileError::TooManyIndices { found: subscripts.len(), span: self.current_span() });
+        }
+        self.expect(Token::RBracket)?;
+
+        // Plain element access unless at least one dimension is a range.
+        if subscripts.iter().all(|s| matches!(s, Subscript::Point(_))) {
+            let indices = subscripts
+                .into_iter()
+                .map(|s| match s {
+                    Subscript::Point(index) => index,
+                    Subscript::Range { .. } => unreachable!(),
+                })
+                .collect();
+            Ok(ExprKind::Index(Box::new(expr), indices))
+        } else {
+            Ok(ExprKind::Slice(Box::new(expr), subscripts))
+        }
+    }
+
+    /// Parses one subscript inside `[...]`: `i`, or a range `start:end:step` with optional parts.
+    fn parse_subscript(&mut self) -> Result<Subscript> {
+        let ends_part = |token: Option<&Token>| matches!(token, Some(Token::Colon | Token::Comma | Token::RBracket));
+
+        let start = if ends_part(self.peek()) { None } else { Some(self.parse_expr()?) };
+        if !self.match_token(Token::Colon) {
+            return match start {
+                Some(index) => Ok(Subscript::Point(index)),
+                None => Err(self.unexpected("index")),
+            };
+        }
+
+        let end = if ends_part(self.peek()) { None } else { Some(self.parse_expr()?) };
+        let step = if self.match_token(Token::Colon) && !ends_part(self.peek()) {
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
+
+        Ok(Subscript::Range { start, end, step })
+    }
+
+    /// Parses the comma-separated elements of one matrix row, up to a closing `]` or a `;`.
+    fn parse_matrix_row(&mut self) -> Result<Vec<Expr>> {
+        let mut row = Vec::new();
+        while !matches!(self.peek(), Some(Token::RBracket | Token::SemiColon)) {
+            row.push(self.parse_expr()?);
+            if !self.match_token(Token::Comma) {
+                break;
+            }
+        }
+        Ok(row)
+    }
+
+    /// Parses a primary expression (numbers, identifiers, builtins, parens, matrices).
+    fn parse_primary(&mut self) -> Result<Expr> {
+        let start = self.current_span();
+        let kind = match self.advance().cloned() {
+            Some(Token::Number(n)) => ExprKind::Number(n),
+            Some(Token::Integer(n)) => ExprKind::Integer(n),
+            Some(Token::True) => ExprKind::Bool(true),
+            Some(Token::False) => ExprKind::Bool(false),
+            Some(Token::If) => return self.parse_if_expr(start),
+            Some(Token::Identifier(name)) if name == "transpose" && self.peek() == Some(&Token::LParen) => {
+                self.advance();
+                let expr = self.parse_expr()?;
+                self.expect(Token::RParen)?;
+                ExprKind::Transpose(Box::new(expr))
+            }
+            Some(Token::Identifier(name)) if self.peek() == Some(&Token::LParen) => {
+                self.advance();
+                let mut args = Vec::new();
+                while !matches!(self.peek(), Some(Token::RParen)) {
+                    args.push(self.parse_expr()?);
+                    if !self.match_token(Token::Comma) {
+                        break;
+                    }
+                }
+                self.expect(Token::RParen)?;
+                ExprKind::Call(name, args)
+            }
+            Some(Token::Identifier(name)) => ExprKind::Identifier(name),
             Some(Token::LParen) => {
                 let expr = self.parse_expr()?;
                 self.expect(Token::RParen)?;
-                Ok(expr)
+                // Keep the parentheses in the span so errors underline the whole group.
+                return Ok(Expr::new(expr.kind, self.span_from(start)));
             }
             Some(Token::LBracket) => {
-                // Check if it's a nested matrix `[[` or just `[`
-                if let Some(Token::LBracket) = self.peek() {
-                    // Nested matrix: [[1, 2], [3, 4]]
-                    let mut rows = Vec::new();
-                    while let Some(Token::LBracket) = self.peek() {
-                        self.advance(); // consume [
-                        let mut row = Vec::new();
-                        while !matches!(self.peek(), Some(Token::RBracket)) {
-                            row.push(self.parse_expr()?);
-                            if matches!(self.peek(), Some(Token::Comma)) {
-                                self.advance();
-                            } else {
-                                break;
-                            }
-                        }
-                        self.expect(Token::RBracket)?; // consume ]
-                        rows.push(row);
-
-                        if matches!(self.peek(), Some(Token::Comma)) {
-                            self.advance();
-                        } else {
-                            break;
-                        }
-                    }
-                    self.expect(Token::RBracket)?; // consume closing outer ]
-                    Ok(Expr::MatrixLiteral(rows))
+                // Rows separated by `;`: [1, 2; 3, 4]. A single row [1, 2, 3] is a 1-row matrix.
+                let mut rows = vec![self.parse_matrix_row()?];
+                while self.match_token(Token::SemiColon) {
+                    rows.push(self.parse_matrix_row()?);
+                }
+                self.expect(Token::RBracket)?;
+
+                // A list of single-row literals is the nested form [[1, 2], [3, 4]]: one row each,
+                // so [[1], [2]] is 2x1. This is purely syntactic: `[a, b]` with row vectors `a`
+                // and `b` in variables joins them side by side like any other blocks, and codegen
+                // rejects a mix such as `[[1, 2], b]`.
+                let is_nested = rows.len() == 1
+                    && !rows[0].is_empty()
+                    && rows[0].iter().all(|e| matches!(&e.kind, ExprKind::MatrixLiteral(inner) if inner.len() == 1));
+                if is_nested {
+                    let rows = rows.remove(0).into_iter().map(|e| match e.kind {
+                        ExprKind::MatrixLiteral(mut inner) => inner.remove(0),
+                        _ => unreachable!(),
+                    });
+                    ExprKind::MatrixLiteral(rows.collect())
                 } else {
-                    // 1D Array/Vector treated as 1-row Matrix: [1, 2, 3] -> [[1, 2, 3]]
-                    let mut row = Vec::new();
-                    while !matches!(self.peek(), Some(Token::RBracket)) {
-                        row.push(self.parse_expr()?);
-                        if matches!(self.peek(), Some(Token::Comma)) {
-                            self.advance();
-                        } else {
-                            break;
-                        }
-                    }
-                    self.expect(Token::RBracket)?;
-                    Ok(Expr::MatrixLiteral(vec![row]))
+                    ExprKind::MatrixLiteral(rows)
                 }
             }
-            t => bail!("Expected factor, found {:?}", t),
-        }
+            found => {
+                // Leave the offending token for error recovery to see.
+                if found.is_some() {
+                    self.pos -= 1;
+                }
+                return Err(CompileError::ParseError { expected: "expression".to_string(), found, span: start });
+            }
+        };
+        Ok(Expr::new(kind, self.span_from(start)))
+    }
+}
+
+/// How a chain of binary operators with the same precedence groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Associativity {
+    /// `a - b - c` is `(a - b) - c`.
+    Left,
+    /// `a ^ b ^ c` is `a ^ (b ^ c)`.
+    Right,
+}
+
+/// The binary operator table: the operator a token stands for, its precedence
+/// (higher binds tighter) and its associativity.
+///
+/// Postfix operators and `!` bind tighter than every entry here; `-` binds like `^`
+/// (see `prefix_operator`).
+fn binary_operator(token: &Token) -> Option<(Op, u8, Associativity)> {
+    use Associativity::{Left, Right};
+
+    let entry = match token {
+        Token::OrOr => (Op::Or, 1, Left),
+        Token::AndAnd => (Op::And, 2, Left),
+        Token::EqEq => (Op::Equal, 3, Left),
+        Token::NotEq => (Op::NotEqual, 3, Left),
+        Token::Less => (Op::Less, 3, Left),
+        Token::LessEq => (Op::LessEqual, 3, Left),
+        Token::Greater => (Op::Greater, 3, Left),
+        Token::GreaterEq => (Op::GreaterEqual, 3, Left),
+        Token::Plus => (Op::Add, 4, Left),
+        Token::Minus => (Op::Subtract, 4, Left),
+        Token::Star => (Op::Multiply, 5, Left),
+        Token::Slash => (Op::Divide, 5, Left),
+        Token::At => (Op::MatMul, 5, Left),
+        Token::DotStar => (Op::ElemMul, 5, Left),
+        Token::DotSlash => (Op::ElemDiv, 5, Left),
+        Token::Caret => (Op::Power, 6, Right),
+        Token::DotCaret => (Op::ElemPower, 6, Right),
+        _ => return None,
+    };
+    Some(entry)
+}
+
+/// The prefix operator table: the unary operator a token stands for and the
+/// precedence its operand is parsed at.
+///
+/// `-` sits at the level of `^`, so it negates a whole power but not a product;
+/// `!` binds tighter than every binary operator.
+fn prefix_operator(token: &Token) -> Option<(UnaryOp, u8)> {
+    match token {
+        Token::Minus => Some((UnaryOp::Neg, 6)),
+        Token::Bang => Some((UnaryOp::Not, 7)),
+        _ => None,
+    }
+}
+
+/// A postfix operator, applied to the expression before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Postfix {
+    /// `A'`.
+    Transpose,
+    /// `A[i, j]` or a slice `A[a:b, :]`.
+    Subscript,
+}
+
+/// The postfix operator table. Postfix operators bind tighter than prefix ones,
+/// so `-A'` is `-(A')`.
+fn postfix_operator(token: &Token) -> Option<Postfix> {
+    match token {
+        Token::Transpose => Some(Postfix::Transpose),
+        Token::LBracket => Some(Postfix::Subscript),
+        _ => None,
     }
 }
@@ -0,0 +1,218 @@
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr};
+use thiserror::Error;
+
+use crate::compiler::diagnostic::{self, Span};
+
+/// The symbol generated code calls to report a runtime error: `(kind, what, a, b, c, d,
+/// span_start, span_end)`, where `a` to `d` are the details of the kind and the span is
+/// the source of the failing expression.
+pub const RAISE_FN: &str = "__matrix_script_raise";
+
+/// The symbol generated code calls after a function call to check whether the
+/// callee raised an error that must be propagated.
+pub const PENDING_FN: &str = "__matrix_script_error_pending";
+
+/// Error kind passed to `RAISE_FN`: operand shapes are incompatible.
+/// Details are `(lhs_rows, lhs_cols, rhs_rows, rhs_cols)`.
+pub const SHAPE_MISMATCH: u64 = 0;
+
+/// Error kind passed to `RAISE_FN`: a `A[i, j]` index is out of range.
+/// Details are `(i, j, rows, cols)`.
+pub const INDEX_OUT_OF_BOUNDS: u64 = 1;
+
+/// Error kind passed to `RAISE_FN`: a linear `v[i]` index is out of range.
+/// Details are `(i, _, rows, cols)`.
+pub const LINEAR_INDEX_OUT_OF_BOUNDS: u64 = 2;
+
+/// Error kind passed to `RAISE_FN`: a slice range is out of bounds or has a non-positive step.
+/// Details are `(start, end, step, len)`.
+pub const INVALID_SLICE: u64 = 3;
+
+/// Error kind passed to `RAISE_FN`: the base of a matrix power is not square.
+/// Details are `(rows, cols, _, _)`.
+pub const NON_SQUARE_MATRIX: u64 = 4;
+
+/// Error kind passed to `RAISE_FN`: a matrix power exponent is not a non-negative integer.
+/// Details are `(exponent.to_bits(), _, _, _)`.
+pub const INVALID_EXPONENT: u64 = 5;
+
+/// Error kind passed to `RAISE_FN`: an index or slice bound is not a whole number in the i64 range.
+/// Details are `(index.to_bits(), _, _, _)`.
+pub const INVALID_INDEX: u64 = 6;
+
+/// An error detected while executing JIT-compiled code.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum RuntimeError {
+    /// The operands of a matrix operation have incompatible shapes.
+    #[error("Shape mismatch in `{op}`: left operand is {}x{}, right operand is {}x{}", lhs.0, lhs.1, rhs.0, rhs.1)]
+    ShapeMismatch {
+        op: String,
+        lhs: (i64, i64),
+        rhs: (i64, i64),
+        span: Span,
+    },
+    /// A two-dimensional index lies outside the matrix.
+    #[error("Index [{}, {}] is out of bounds for a {}x{} matrix", index.0, index.1, shape.0, shape.1)]
+    IndexOutOfBounds { index: (i64, i64), shape: (i64, i64), span: Span },
+    /// A linear index lies outside the matrix.
+    #[error("Index [{index}] is out of bounds for a {}x{} matrix", shape.0, shape.1)]
+    LinearIndexOutOfBounds { index: i64, shape: (i64, i64), span: Span },
+    /// A slice range does not fit its dimension, or its step is not positive.
+    #[error("Slice {start}:{end}:{step} is invalid for a dimension of length {len}")]
+    InvalidSlice { start: i64, end: i64, step: i64, len: i64, span: Span },
+    /// A matrix was raised to a power but is not square.
+    #[error("Operator `{op}` requires a square matrix, found a {}x{} matrix", shape.0, shape.1)]
+    NonSquareMatrix { op: String, shape: (i64, i64), span: Span },
+    /// A matrix was raised to a power that is not a non-negative integer.
+    #[error("Operator `{op}` requires a non-negative integer exponent for matrices, found {exponent}")]
+    InvalidExponent { op: String, exponent: f64, span: Span },
+    /// An index or slice bound is NaN, infinite, fractional or too large to be an index.
+    #[error("Index {index} is not a valid matrix index")]
+    InvalidIndex { index: f64, span: Span },
+    /// Generated code raised an error of a kind this runtime does not know.
+    #[error("Unknown runtime error (kind {kind})")]
+    Unknown { kind: u64, span: Span },
+}
+
+impl RuntimeError {
+    /// Returns the location in the source of the expression that failed.
+    pub fn span(&self) -> Span {
+        match self {
+            RuntimeError::ShapeMismatch { span, .. }
+            | RuntimeError::IndexOutOfBounds { span, .. }
+            | RuntimeError::LinearIndexOutOfBounds { span, .. }
+            | RuntimeError::InvalidSlice { span, .. }
+            | RuntimeError::NonSquareMatrix { span, .. }
+            | RuntimeError::InvalidExponent { span, .. }
+            | RuntimeError::InvalidIndex { span, .. }
+            | RuntimeError::Unknown { span, .. } => *span,
+        }
+    }
+
+    /// Renders the error with its file name, line, column and source snippet
+    /// (see `diagnostic::render`).
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        diagnostic::render(&self.to_string(), self.span(), file_name, source)
+    }
+}
+
+thread_local! {
+    /// The first error raised by generated code on this thread.
+    static PENDING_ERROR: RefCell<Option<RuntimeError>> = const { RefCell::new(None) };
+}
+
+/// Records a runtime error raised by generated code.
+///
+/// Generated code returns from the current function right after calling this,
+/// and `Jit` turns the recorded error into an `Err` once the entry point returns.
+///
+/// # Safety
+///
+/// `what` must be null or point to a NUL-terminated string.
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn matrix_script_raise(kind: u64, what: *const c_char, a: i64, b: i64, c: i64, d: i64, span_start: i64, span_end: i64) {
+    let what = if what.is_null() {
+        String::new()
+    } else {
+        unsafe { CStr::from_ptr(what) }.to_string_lossy().into_owned()
+    };
+
+    let span = Span::new(span_start as usize, span_end as usize);
+    let error = match kind {
+        SHAPE_MISMATCH => RuntimeError::ShapeMismatch { op: what, lhs: (a, b), rhs: (c, d), span },
+        INDEX_OUT_OF_BOUNDS => RuntimeError::IndexOutOfBounds { index: (a, b), shape: (c, d), span },
+        LINEAR_INDEX_OUT_OF_BOUNDS => RuntimeError::LinearIndexOutOfBounds { index: a, shape: (c, d), span },
+        INVALID_SLICE => RuntimeError::InvalidSlice { start: a, end: b, step: c, len: d, span },
+        NON_SQUARE_MATRIX => RuntimeError::NonSquareMatrix { op: what, shape: (a, b), span },
+        INVALID_EXPONENT => RuntimeError::InvalidExponent { op: what, exponent: f64::from_bits(a as u64), span },
+        INVALID_INDEX => RuntimeError::InvalidIndex { index: f64::from_bits(a as u64), span },
+        // Panicking here would unwind into generated code.
+        _ => RuntimeError::Unknown { kind, span },
+    };
+
+    PENDING_ERROR.with(|pending| {
+        pending.borrow_mut().get_or_insert(error);
+    });
+}
+
+/// Returns whether a runtime error has been raised and not yet taken.
+pub extern "C" fn matrix_script_error_pending() -> bool {
+    PENDING_ERROR.with(|pending| pending.borrow().is_some())
+}
+
+/// Takes the pending runtime error, if any, leaving none behind.
+pub fn take_error() -> Option<RuntimeError> {
+    PENDING_ERROR.with(|pending| pending.borrow_mut().take())
+}
+
+/// A matrix as laid out by generated code: `{ double* data, i64 rows, i64 cols }`,
+/// with `rows * cols` elements in row-major order.
+///
+/// Host functions receive their matrix arguments as `*const Matrix` and must not free
+/// them; matrices they return must come from `alloc_matrix`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Matrix {
+    pub data: *mut f64,
+    pub rows: i64,
+    pub cols: i64,
+}
+
+impl Matrix {
+    /// Returns the elements of the matrix in row-major order.
+    ///
+    /// # Safety
+    ///
+    /// `data` must point to `rows * cols` initialized elements, as it does for any
+    /// matrix built by generated code or `alloc_matrix`.
+    pub unsafe fn as_slice(&self) -> &[f64] {
+        let len = (self.rows * self.cols) as usize;
+        if len == 0 {
+            &[]
+        } else {
+            unsafe { std::slice::from_raw_parts(self.data, len) }
+        }
+    }
+}
+
+extern "C" {
+    /// Generated code allocates matrices with `malloc`, so they are released with `free`
+    /// and matrices made on the host are allocated the same way.
+    fn malloc(size: usize) -> *mut c_void;
+    fn free(ptr: *mut c_void);
+}
+
+/// Copies `data` into a new `rows x cols` matrix allocated the way generated code
+/// allocates matrices, so it can be handed to (or returned into) a script.
+///
+/// # Panics
+///
+/// Panics if `data` does not hold `rows * cols` elements.
+pub fn alloc_matrix(rows: usize, cols: usize, data: &[f64]) -> *mut Matrix {
+    assert_eq!(data.len(), rows * cols, "a {}x{} matrix needs {} elements", rows, cols, rows * cols);
+
+    unsafe {
+        let buffer = malloc(std::mem::size_of_val(data)).cast::<f64>();
+        if !data.is_empty() {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), buffer, data.len());
+        }
+
+        let matrix = malloc(std::mem::size_of::<Matrix>()).cast::<Matrix>();
+        matrix.write(Matrix { data: buffer, rows: rows as i64, cols: cols as i64 });
+        matrix
+    }
+}
+
+/// Frees a matrix and its data buffer.
+///
+/// # Safety
+///
+/// `matrix` must have been allocated by `alloc_matrix` or by generated code, and must
+/// not be used afterwards.
+pub unsafe fn free_matrix(matrix: *mut Matrix) {
+    unsafe {
+        free((*matrix).data.cast());
+        free(matrix.cast());
+    }
+}
@@ -0,0 +1,137 @@
+use inkwell::context::Context;
+use inkwell::types::BasicTypeEnum;
+use std::fmt;
+
+use crate::compiler::ast::Type;
+use crate::compiler::codegen::CodeGen;
+use crate::compiler::error::Error;
+use crate::compiler::host::HostFunction;
+use crate::compiler::jit::{Arg, Jit, Value};
+use crate::compiler::parser::Parser;
+
+/// Compiles MatrixScript programs to native code. The engine owns the LLVM context,
+/// so it must outlive every program it compiles:
+///
+/// ```no_run
+/// use matrix_script::{Arg, Engine};
+///
+/// let engine = Engine::new();
+/// let program = engine.compile("fn predict(X: Matrix, w: Matrix) { return X @ w; }")?;
+/// let x = [1.0, 2.0, 3.0, 4.0];
+/// let w = [0.5, 0.5];
+/// let y = program.call("predict", &[
+///     Arg::Matrix { rows: 2, cols: 2, data: &x },
+///     Arg::Matrix { rows: 2, cols: 1, data: &w },
+/// ])?;
+/// # Ok::<(), matrix_script::Error>(())
+/// ```
+pub struct Engine {
+    context: Context,
+    host_functions: Vec<HostFunction>,
+}
+
+impl Engine {
+    /// Creates an engine with a fresh LLVM context.
+    pub fn new() -> Self {
+        Self { context: Context::create(), host_functions: Vec::new() }
+    }
+
+    /// Makes a Rust function callable from every program compiled afterwards,
+    /// replacing any registered earlier under the same name.
+    pub fn register_function(&mut self, function: HostFunction) -> &mut Self {
+        self.host_functions.retain(|f| f.name != function.name);
+        self.host_functions.push(function);
+        self
+    }
+
+    /// Parses, type-checks and JIT-compiles a program.
+    pub fn compile(&self, source: &str) -> Result<CompiledProgram<'_>, Error> {
+        let mut parser = Parser::new(source);
+        let program = parser.parse_program()?;
+
+        let mut codegen = CodeGen::new(&self.context, "matrix_script_module");
+        for host in &self.host_functions {
+            codegen.register_host_function(host.clone());
+        }
+        codegen.compile_program(&program)?;
+
+        let functions = program
+            .functions
+            .iter()
+            .map(|function| {
+                let fn_type = codegen.module().get_function(&function.name).unwrap().get_type();
+                let params = function
+                    .params
+                    .iter()
+                    .zip(fn_type.get_param_types())
+                    .map(|(param, ty)| (param.name.clone(), value_type(ty)))
+                    .collect();
+                FunctionSignature { name: function.name.clone(), params, ret: value_type(fn_type.get_return_type().unwrap()) }
+            })
+            .collect();
+
+        // The execution engine takes over the module, so `codegen` can go.
+        let jit = Jit::from_codegen(&codegen)?;
+        Ok(CompiledProgram { jit, functions })
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A program compiled by an `Engine`, ready to be called any number of times.
+pub struct CompiledProgram<'engine> {
+    jit: Jit<'engine>,
+    functions: Vec<FunctionSignature>,
+}
+
+impl CompiledProgram<'_> {
+    /// The compiled signatures of the program's functions, in source order.
+    pub fn functions(&self) -> &[FunctionSignature] {
+        &self.functions
+    }
+
+    /// The compiled signature of the function with the given name.
+    pub fn function(&self, name: &str) -> Option<&FunctionSignature> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    /// Calls a function with arguments from Rust (see `Jit::call`).
+    pub fn call(&self, name: &str, args: &[Arg]) -> Result<Value, Error> {
+        self.jit.call(name, args)
+    }
+
+    /// Runs a function that takes no arguments, such as `main`.
+    pub fn run(&self, name: &str) -> Result<Value, Error> {
+        self.jit.run(name)
+    }
+}
+
+/// The parameter and return types a function was compiled with, after inference.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionSignature {
+    pub name: String,
+    pub params: Vec<(String, Type)>,
+    pub ret: Type,
+}
+
+impl fmt::Display for FunctionSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params: Vec<String> = self.params.iter().map(|(name, ty)| format!("{}: {}", name, ty)).collect();
+        write!(f, "fn {}({}) -> {}", self.name, params.join(", "), self.ret)
+    }
+}
+
+/// Maps a compiled LLVM type back to the language type it represents.
+fn value_type(ty: BasicTypeEnum<'_>) -> Type {
+    if ty.is_pointer_type() {
+        Type::Matrix
+    } else if ty.is_int_type() {
+        Type::Bool
+    } else {
+        Type::Scalar
+    }
+}
@@ -1 +1,7 @@
 pub mod compiler;
+pub mod engine;
+
+pub use compiler::error::Error;
+pub use compiler::host::HostFunction;
+pub use compiler::jit::{Arg, Value};
+pub use engine::{CompiledProgram, Engine, FunctionSignature};
@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
-use clap::Parser as ClapParser;
-use inkwell::context::Context as InkwellContext;
-use matrix_script::compiler; // Use the library module
+use clap::{Parser as ClapParser, ValueEnum};
+use matrix_script::compiler::format::{error_to_json, format_value, io_error_to_json, value_to_csv, value_to_json, FormatOptions};
+use matrix_script::{Engine, Error, Value};
 use std::fs;
 use std::path::PathBuf;
 
@@ -13,30 +13,74 @@ struct Cli {
     /// The file to run
     #[arg(value_name = "FILE")]
     file: PathBuf,
+
+    /// Maximum number of digits shown after the decimal point
+    #[arg(long, default_value_t = FormatOptions::default().precision)]
+    precision: usize,
+
+    /// How the result (and, for json, any error) is written to stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+}
+
+/// The formats the result of a script can be printed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable, with matrices as aligned tables
+    Text,
+    /// A JSON object with the value's type and shape
+    Json,
+    /// Comma-separated values, one line per matrix row
+    Csv,
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    let source = fs::read_to_string(&cli.file)
-        .with_context(|| format!("Failed to read file {:?}", cli.file))?;
+    let source = match fs::read_to_string(&cli.file) {
+        Ok(source) => source,
+        Err(err) if cli.output == OutputFormat::Json => {
+            println!("{}", io_error_to_json(&format!("Failed to read file {:?}: {}", cli.file, err)));
+            std::process::exit(1);
+        }
+        Err(err) => return Err(err).with_context(|| format!("Failed to read file {:?}", cli.file)),
+    };
 
-    // 1. Lexing & Parsing
-    let mut parser = compiler::parser::Parser::new(&source)?;
-    let program = parser.parse_program()?;
+    match run(&source) {
+        Ok(value) => match cli.output {
+            OutputFormat::Text => {
+                let options = FormatOptions { precision: cli.precision, ..FormatOptions::default() };
+                match &value {
+                    Value::Matrix { rows, cols, .. } => println!("Result: {}x{} matrix\n{}", rows, cols, format_value(&value, &options)),
+                    _ => println!("Result: {}", format_value(&value, &options)),
+                }
+            }
+            OutputFormat::Json => println!("{}", value_to_json(&value)),
+            OutputFormat::Csv => println!("{}", value_to_csv(&value)),
+        },
+        Err(err) => {
+            // Compile and runtime errors are shown with the offending line of the script.
+            let file_name = cli.file.display().to_string();
+            match err {
+                // Tools reading JSON get the error on stdout, where they expect the result.
+                err if cli.output == OutputFormat::Json => println!("{}", error_to_json(&err, &source)),
+                Error::Parse(errs) => eprint!("{}", errs.render(&file_name, &source)),
+                Error::Compile(err) => eprint!("{}", err.render(&file_name, &source)),
+                Error::Runtime(err) => eprint!("{}", err.render(&file_name, &source)),
+                Error::Call(err) => eprintln!("error: {}", err),
+            }
+            std::process::exit(1);
+        }
+    }
 
-    // 2. LLVM Codegen
-    let context = InkwellContext::create();
-    let mut codegen = compiler::codegen::CodeGen::new(&context, "matrix_script_module");
-    codegen.compile_program(&program)?;
+    Ok(())
+}
 
-    // 3. JIT Execution
-    let jit = compiler::jit::Jit::new(codegen.module())?;
+/// Compiles and runs a script, returning the result of `main`.
+fn run(source: &str) -> Result<Value, Error> {
+    let engine = Engine::new();
+    let program = engine.compile(source)?;
 
     // For now we assume the entry point is "main"
-    let result = jit.run("main")?;
-
-    println!("Result: {}", result);
-
-    Ok(())
+    program.run("main")
 }
@@ -0,0 +1,17 @@
+use matrix_script::compiler::error::CompileError;
+use matrix_script::compiler::jit::Value;
+use matrix_script::{Engine, Error};
+
+/// Compiles `source` with a fresh engine and runs its `main`.
+pub fn run(source: &str) -> Result<Value, Error> {
+    Engine::new().compile(source)?.run("main")
+}
+
+/// Compiles `source` with a fresh engine, expecting it to be rejected.
+pub fn compile_error(source: &str) -> CompileError {
+    match Engine::new().compile(source) {
+        Err(Error::Compile(err)) => err,
+        Err(err) => panic!("Expected a compile error, found {:?}", err),
+        Ok(_) => panic!("Expected a compile error"),
+    }
+}
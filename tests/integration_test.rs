@@ -1,5 +1,13 @@
 use matrix_script::compiler;
 use inkwell::context::Context;
+use matrix_script::compiler::diagnostic::Span;
+use matrix_script::compiler::error::CompileError;
+use matrix_script::compiler::jit::Value;
+use matrix_script::compiler::lexer::Token;
+use matrix_script::{Engine, Error};
+
+mod common;
+use common::{compile_error, run};
 
 #[test]
 fn test_math_expression() {
@@ -8,7 +16,7 @@ fn test_math_expression() {
     let code_body = "3.0 + 2.0";
     let source = format!("fn main() {{ return {}; }}", code_body);
 
-    let mut parser = compiler::parser::Parser::new(&source).expect("Failed to create parser");
+    let mut parser = compiler::parser::Parser::new(&source);
     let program = parser.parse_program().expect("Failed to parse program");
 
     let context = Context::create();
@@ -18,7 +26,7 @@ fn test_math_expression() {
     let jit = compiler::jit::Jit::new(codegen.module()).expect("Failed to create JIT");
     let result = jit.run("main").expect("Failed to run main");
 
-    assert_eq!(result, 5.0);
+    assert_eq!(result, Value::Scalar(5.0));
 }
 
 #[test]
@@ -31,7 +39,7 @@ fn test_complex_math() {
     }
     ";
 
-    let mut parser = compiler::parser::Parser::new(source).expect("Failed to create parser");
+    let mut parser = compiler::parser::Parser::new(source);
     let program = parser.parse_program().expect("Failed to parse program");
 
     let context = Context::create();
@@ -41,5 +49,730 @@ fn test_complex_math() {
     let jit = compiler::jit::Jit::new(codegen.module()).expect("Failed to create JIT");
     let result = jit.run("main").expect("Failed to run main");
 
-    assert_eq!(result, 205.0);
+    assert_eq!(result, Value::Scalar(205.0));
+}
+
+#[test]
+fn test_assignment_and_compound_assignment() {
+    let source = "
+    fn main() {
+        let x = 1.0;
+        x = x + 1.0;
+        x *= 10.0;
+        let A = [[1.0, 2.0], [3.0, 4.0]];
+        A += [[10.0, 20.0], [30.0, 40.0]];
+        A[0, 1] = 100.0;
+        A[1, 0] -= 3.0;
+        return x + A[0, 1] + A[1, 0] + A[1, 1];
+    }
+    ";
+
+    let result = run(source).expect("Failed to run main");
+
+    assert_eq!(result, Value::Scalar(20.0 + 100.0 + 30.0 + 44.0));
+}
+
+#[test]
+fn test_assignment_type_mismatch_is_rejected() {
+    let source = "
+    fn main() {
+        let x = 1.0;
+        x = [1.0, 2.0];
+        return x;
+    }
+    ";
+
+    let err = compile_error(source);
+
+    assert_eq!(err.to_string(), "Cannot assign a matrix value to `x`, which holds a scalar");
+}
+
+#[test]
+fn test_function_calls_with_parameters() {
+    let source = "
+    fn main() {
+        let A = [[1.0, 2.0], [3.0, 4.0]];
+        return trace(gram(A)) + square(3.0);
+    }
+
+    fn gram(M) {
+        return M' @ M;
+    }
+
+    fn trace(M: Matrix) {
+        return M[0, 0] + M[1, 1];
+    }
+
+    fn square(x: f64) {
+        return x * x;
+    }
+    ";
+
+    let mut parser = compiler::parser::Parser::new(source);
+    let program = parser.parse_program().expect("Failed to parse program");
+    assert_eq!(program.functions[2].to_string(), "fn trace(M: Matrix) {\n    return (M[0, 0] + M[1, 1]);\n}");
+
+    let result = run(source).expect("Failed to run main");
+
+    assert_eq!(result, Value::Scalar(30.0 + 9.0));
+}
+
+#[test]
+fn test_runtime_error_propagates_through_calls() {
+    let source = "
+    fn main() {
+        let x = add(1.0, 2.0);
+        return x[0];
+    }
+
+    fn add(a, b) {
+        return [a, b] + [a, b, a];
+    }
+    ";
+
+    let err = run(source).unwrap_err();
+
+    assert_eq!(err.to_string(), "Shape mismatch in `+`: left operand is 1x2, right operand is 1x3");
+}
+
+#[test]
+fn test_call_argument_count_is_checked() {
+    let source = "
+    fn main() {
+        return square(1.0, 2.0);
+    }
+
+    fn square(x) {
+        return x * x;
+    }
+    ";
+
+    let err = compile_error(source);
+
+    assert_eq!(err.to_string(), "Function `square` takes 1 arguments, found 2");
+}
+
+#[test]
+fn test_if_else_and_comparisons() {
+    let source = "
+    fn main() {
+        let x = 3.0;
+        let sign = if x < 0.0 { 0.0 - 1.0 } else { 1.0 };
+        if x > 1.0 && sign == 1.0 {
+            let doubled = x * 2.0;
+            x = doubled;
+        } else if !(x == 0.0) {
+            x = 0.0;
+        }
+        return fact(x) + classify(0.0 - 2.0) * 1000.0;
+    }
+
+    fn fact(n) {
+        if n <= 1.0 {
+            return 1.0;
+        }
+        return n * fact(n - 1.0);
+    }
+
+    fn classify(x) {
+        if x < 0.0 || x != x { return 0.0 - 1.0; } else if x == 0.0 { return 0.0; } else { return 1.0; }
+    }
+    ";
+
+    let mut parser = compiler::parser::Parser::new(source);
+    let program = parser.parse_program().expect("Failed to parse program");
+    assert_eq!(program.functions[1].to_string(), "fn fact(n) {\n    if (n <= 1) { return 1; }\n    return (n * fact((n - 1)));\n}");
+
+    let result = run(source).expect("Failed to run main");
+
+    assert_eq!(result, Value::Scalar(720.0 - 1000.0));
+}
+
+#[test]
+fn test_if_condition_must_be_boolean() {
+    let source = "
+    fn main() {
+        if 1.0 { return 1.0; }
+        return 0.0;
+    }
+    ";
+
+    let err = compile_error(source);
+
+    assert_eq!(err.to_string(), "Condition must be a boolean, found a scalar");
+}
+
+#[test]
+fn test_return_paths() {
+    let compiles = |source: &str| Engine::new().compile(source).is_ok();
+
+    // Returning on every branch of an `else if` chain is enough.
+    let source = "
+    fn sign(x) {
+        if x < 0.0 { return [0.0 - 1.0]; } else if x > 0.0 { return [1.0]; } else { return [0.0]; }
+    }
+    fn main() { return sign(2.0)[0]; }
+    ";
+    assert!(compiles(source));
+
+    // A matrix function that could fall off its end has no matrix to return.
+    let source = "
+    fn f(c: bool) {
+        if c { return [1.0, 2.0]; }
+    }
+    ";
+    let err = compile_error(source);
+    assert_eq!(err.to_string(), "Function `f` does not return a value on every path");
+    assert_eq!(err.span(), Some(Span::new(5, 18)));
+
+    let source = "fn f(n) { while n > 0.0 { return n; } }";
+    assert!(matches!(compile_error(source), CompileError::MissingReturn { .. }));
+
+    // `while true` can only be left through a `break` or a `return`.
+    let source = "fn f(n) { while true { if n > 10.0 { return [n]; } n *= 2.0; } } fn main() { return f(3.0)[0]; }";
+    assert_eq!(run(source), Ok(Value::Scalar(12.0)));
+    let source = "fn f(n) { while true { while true { break; } return n; } }";
+    assert!(compiles(source));
+    let source = "fn f(n) { while true { if n > 10.0 { break; } return n; } }";
+    assert!(matches!(compile_error(source), CompileError::MissingReturn { .. }));
+
+    // Every return is checked, not just the first, and the error points at the one that conflicts.
+    let source = "fn f(c: bool) { if c { return 1.0; } if !c { return true; } return 2.0; }";
+    let err = compile_error(source);
+    assert_eq!(err.to_string(), "Function `f` returns a boolean here, but a scalar elsewhere");
+    assert_eq!(err.span(), Some(Span::new(45, 57)));
+
+    // A recursive call takes the type of the other returns once inference settles.
+    let source = "
+    fn ones(n) {
+        if n > 1.0 { return [ones(n - 1.0), 1.0]; }
+        return [1.0];
+    }
+    ";
+    assert!(compiles(source));
+}
+
+#[test]
+fn test_loops() {
+    let source = "
+    fn main() {
+        let A = [[2.0, 1.0], [1.0, 3.0]];
+        let v = [[1.0], [1.0]];
+        for k in 0..50 {
+            let w = A @ v;
+            v = w * (1.0 / w[0]);
+        }
+
+        let total = 0.0;
+        let i = 0.0;
+        while true {
+            i += 1.0;
+            if i > 10.0 { break; }
+            if i == 3.0 { continue; }
+            total += i;
+        }
+        for j in 0..3 {
+            j = 100.0;
+            total += 1000.0;
+        }
+        return v[1] * 10000.0 + total;
+    }
+    ";
+
+    let Value::Scalar(result) = run(source).expect("Failed to run main") else {
+        panic!("main should return a scalar");
+    };
+
+    // The dominant eigenvector of A is [1, golden ratio]; the loops add 52 + 3000.
+    let golden = (1.0 + 5.0_f64.sqrt()) / 2.0;
+    assert!((result - (golden * 10000.0 + 3052.0)).abs() < 1e-6);
+}
+
+#[test]
+fn test_break_outside_loop() {
+    let source = "
+    fn main() {
+        break;
+        return 0.0;
+    }
+    ";
+
+    let err = compile_error(source);
+
+    assert_eq!(err.to_string(), "`break` outside of a loop");
+}
+
+#[test]
+fn test_parse_error_location() {
+    let source = "fn main() {\n    let y = f(1, , 2);\n}\n";
+
+    let mut parser = compiler::parser::Parser::new(source);
+    let err = parser.parse_program().unwrap_err();
+
+    assert_eq!(
+        err.errors,
+        vec![CompileError::ParseError { expected: "expression".to_string(), found: Some(Token::Comma), span: Span::new(29, 30) }]
+    );
+    assert_eq!(
+        err.render("script.ms", source),
+        "error: Expected expression, found `,`\n --> script.ms:2:18\n  |\n2 |     let y = f(1, , 2);\n  |                  ^\n"
+    );
+}
+
+#[test]
+fn test_codegen_error_location() {
+    let source = "fn main() {\n    let x = [1, 2];\n    return x + missing;\n}\n";
+
+    let err = compile_error(source);
+
+    assert_eq!(err, CompileError::UndefinedVariable { name: "missing".to_string(), span: Span::new(47, 54) });
+    assert_eq!(
+        err.render("script.ms", source),
+        "error: Variable not found: missing\n --> script.ms:3:16\n  |\n3 |     return x + missing;\n  |                ^^^^^^^\n"
+    );
+}
+
+#[test]
+fn test_parser_recovers_from_errors() {
+    let source = "
+    fn helper(x) {
+        let y = x + ;
+        if y > 1.0 {
+            let z = (1.0 + 2.0;
+            return z;
+        }
+        return y * 2.0;
+    }
+
+    fn broken( {
+        return 1.0;
+    }
+
+    fn main() {
+        let a = 1.0 2.0;
+        return helper(3.0);
+    }
+    ";
+
+    let mut parser = compiler::parser::Parser::new(source);
+    let err = parser.parse_program().unwrap_err();
+
+    let messages: Vec<String> = err.errors.iter().map(|e| e.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Expected expression, found `;`",
+            "Expected `)`, found `;`",
+            "Expected parameter name, found `{`",
+            "Expected `;`, found number `2`",
+        ]
+    );
+
+    // The statements and functions that parsed are kept.
+    let names: Vec<&str> = err.program.functions.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, vec!["helper", "main"]);
+    assert_eq!(err.program.functions[0].to_string(), "fn helper(x) {\n    if (y > 1) { return z; }\n    return (y * 2);\n}");
+
+    // Invalid tokens are skipped rather than ending the parse, and a `;` between
+    // matrix rows doesn't end the statement being skipped.
+    let source = "
+    fn main() {
+        let a = 1 $ 2;
+        let A = [1 2; 3, 4];
+        let b = 3 +;
+        return a;
+    }
+    ";
+    let err = compiler::parser::Parser::new(source).parse_program().unwrap_err();
+    let messages: Vec<String> = err.errors.iter().map(|e| e.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Lexer error: found invalid token `$`",
+            "Expected `;`, found integer `2`",
+            "Expected `]`, found integer `2`",
+            "Expected expression, found `;`",
+        ]
+    );
+    assert_eq!(err.program.functions[0].to_string(), "fn main() {\n    return a;\n}");
+}
+
+#[test]
+fn test_numeric_literals() {
+    use logos::Logos;
+
+    let tokens: Vec<Token> = Token::lexer("1e-6 .5 1_000_000 2.5E+3 0..n inf nan 42")
+        .map(|t| t.expect("Failed to lex"))
+        .collect();
+    assert_eq!(tokens[0], Token::Number(1e-6));
+    assert_eq!(tokens[1], Token::Number(0.5));
+    assert_eq!(tokens[2], Token::Integer(1_000_000));
+    assert_eq!(tokens[3], Token::Number(2500.0));
+    assert_eq!(&tokens[4..7], &[Token::Integer(0), Token::DotDot, Token::Identifier("n".to_string())]);
+    assert_eq!(&tokens[7..9], &[Token::Identifier("inf".to_string()), Token::Identifier("nan".to_string())]);
+    assert_eq!(tokens[9], Token::Integer(42));
+
+    let source = "
+    fn main() {
+        let tol = 1e-12;
+        let big = 1_000;
+        if tol < .5 && big < inf && nan != nan {
+            return big * 2 + 1.5e1;
+        }
+        return 0;
+    }
+    ";
+
+    assert_eq!(run(source).expect("Failed to run main"), Value::Scalar(2015.0));
+
+    // `inf` and `nan` are constants only until a variable takes the name.
+    let source = "fn main() { let x = -inf; let nan = 2; return [x, nan, inf]; }";
+    let expected = Value::Matrix { rows: 1, cols: 3, data: vec![f64::NEG_INFINITY, 2.0, f64::INFINITY] };
+    assert_eq!(run(source).expect("Failed to run main"), expected);
+    assert_eq!(run("fn inf(nan) { return nan * 2; } fn main() { return inf(3); }").expect("Failed to run main"), Value::Scalar(6.0));
+}
+
+#[test]
+fn test_power_operator() {
+    let source = "
+    fn main() {
+        let a = 2 ^ 3 ^ 2 * 2;
+        let b = 0 - 2 ^ 2;
+        return a + b + 9 ^ .5;
+    }
+    ";
+
+    let mut parser = compiler::parser::Parser::new(source);
+    let program = parser.parse_program().expect("Failed to parse program");
+    // `^` binds tighter than `*` and groups to the right.
+    assert_eq!(program.functions[0].body[0].to_string(), "let a = ((2 ^ (3 ^ 2)) * 2);");
+    assert_eq!(program.functions[0].body[1].to_string(), "let b = (0 - (2 ^ 2));");
+
+    assert_eq!(run(source).expect("Failed to run main"), Value::Scalar(1024.0 - 4.0 + 3.0));
+}
+
+#[test]
+fn test_unary_minus() {
+    let source = "
+    fn main() {
+        let x = -3;
+        x = -x;
+        let A = -[1, -2; 3, 4];
+        let v = [10, 20, 30];
+        return [x, -2 ^ 2, A[0, 1], -A[1, 0], v[-1 + 2]];
+    }
+    ";
+    assert_eq!(run(source).expect("Failed to run main"), Value::Matrix { rows: 1, cols: 5, data: vec![3.0, -4.0, 2.0, 3.0, 20.0] });
+
+    let err = compile_error("fn main() { return -true; }");
+    assert_eq!(err.to_string(), "Operator - is not defined for booleans");
+}
+
+#[test]
+fn test_operator_precedence() {
+    let cases = [
+        ("a || b && c", "(a || (b && c))"),
+        ("a && b || c", "((a && b) || c)"),
+        ("a && b == c", "(a && (b == c))"),
+        ("a == b < c", "((a == b) < c)"),
+        ("a != b + c", "(a != (b + c))"),
+        ("a <= b - c", "(a <= (b - c))"),
+        ("a > b * c", "(a > (b * c))"),
+        ("a >= b / c", "(a >= (b / c))"),
+        ("a + b @ c", "(a + (b @ c))"),
+        ("a - b .* c", "(a - (b .* c))"),
+        ("a + b ./ c", "(a + (b ./ c))"),
+        ("a - b - c", "((a - b) - c)"),
+        ("a / b * c", "((a / b) * c)"),
+        ("a @ b .* c", "((a @ b) .* c)"),
+        ("a * b ^ c", "(a * (b ^ c))"),
+        ("a .* b .^ c", "(a .* (b .^ c))"),
+        ("a ^ b ^ c", "(a ^ (b ^ c))"),
+        ("a .^ b ^ c", "(a .^ (b ^ c))"),
+        ("a' ^ b", "(a' ^ b)"),
+        ("a ^ b'", "(a ^ b')"),
+        ("a[0] ^ b", "(a[0] ^ b)"),
+        ("!a && b", "(!a && b)"),
+        ("!a == b", "(!a == b)"),
+        ("(a + b) * c", "((a + b) * c)"),
+        ("-a ^ b", "-(a ^ b)"),
+        ("-a * b", "(-a * b)"),
+        ("a ^ -b", "(a ^ -b)"),
+        ("a - -b", "(a - -b)"),
+        ("-a'", "-a'"),
+        ("-a[0] + b", "(-a[0] + b)"),
+    ];
+
+    for (source, expected) in cases {
+        let source = format!("fn main() {{ return {}; }}", source);
+        let mut parser = compiler::parser::Parser::new(&source);
+        let program = parser.parse_program().expect("Failed to parse program");
+        assert_eq!(program.functions[0].body[0].to_string(), format!("return {};", expected), "in `{}`", source);
+    }
+}
+
+#[test]
+fn test_typed_results() {
+    let cases = [
+        ("fn main() { return 1.5; }", Value::Scalar(1.5)),
+        ("fn main() { return 1 < 2; }", Value::Bool(true)),
+        ("fn main() { return !true; }", Value::Bool(false)),
+        ("fn main() { return [1, 2; 3, 4]'; }", Value::Matrix { rows: 2, cols: 2, data: vec![1.0, 3.0, 2.0, 4.0] }),
+        ("fn main() { return [1, 2, 3]; }", Value::Matrix { rows: 1, cols: 3, data: vec![1.0, 2.0, 3.0] }),
+    ];
+
+    for (source, expected) in cases {
+        assert_eq!(run(source).expect("Failed to run main"), expected, "in `{}`", source);
+    }
+
+    let matrix = |rows, cols| Value::Matrix { rows, cols, data: (0..rows * cols).map(|n| n as f64).collect() };
+    assert_eq!(matrix(2, 2).to_string(), "[[0, 1], [2, 3]]");
+    assert_eq!(matrix(3, 0).to_string(), "[[], [], []]");
+    assert_eq!(matrix(0, 3).to_string(), "[]");
+    assert_eq!(matrix(0, 0).to_string(), "[]");
+}
+
+#[test]
+fn test_value_formatting() {
+    use matrix_script::compiler::format::{format_value, FormatOptions};
+
+    let options = FormatOptions::default();
+    assert_eq!(format_value(&Value::Scalar(205.0), &options), "205");
+    assert_eq!(format_value(&Value::Scalar(1.0 / 3.0), &options), "0.333333");
+    assert_eq!(format_value(&Value::Scalar(2.5e10), &options), "2.5e+10");
+    assert_eq!(format_value(&Value::Scalar(f64::NAN), &options), "nan");
+    assert_eq!(format_value(&Value::Bool(true), &options), "true");
+
+    // Columns are padded so the decimal points line up.
+    let matrix = Value::Matrix { rows: 2, cols: 2, data: vec![1.5, -2.0, 10.0, 0.25] };
+    assert_eq!(format_value(&matrix, &options), "[[ 1.50 -2.00]\n [10.00  0.25]]");
+    let short = FormatOptions { precision: 1, ..options };
+    assert_eq!(format_value(&matrix, &short), "[[ 1.5 -2.0]\n [10.0  0.2]]");
+
+    // One tiny element switches the whole matrix to scientific notation.
+    let matrix = Value::Matrix { rows: 1, cols: 2, data: vec![1e-5, 1.0] };
+    assert_eq!(format_value(&matrix, &options), "[[1e-05 1e+00]]");
+
+    // Big matrices keep `edge_items` rows and columns at each edge.
+    let data = (0..100).map(f64::from).collect();
+    let matrix = Value::Matrix { rows: 10, cols: 10, data };
+    let elided = FormatOptions { threshold: 50, edge_items: 2, ..options };
+    assert_eq!(
+        format_value(&matrix, &elided),
+        "[[ 0  1 ...  8  9]\n [10 11 ... 18 19]\n ...\n [80 81 ... 88 89]\n [90 91 ... 98 99]]"
+    );
+}
+
+#[test]
+fn test_machine_readable_output() {
+    use matrix_script::compiler::format::{error_to_json, io_error_to_json, value_to_csv, value_to_json};
+
+    let matrix = Value::Matrix { rows: 2, cols: 3, data: vec![1.0, 2.5, -3.0, 4.0, f64::NAN, 6.0] };
+    assert_eq!(
+        value_to_json(&matrix),
+        r#"{"type": "matrix", "rows": 2, "cols": 3, "data": [[1, 2.5, -3], [4, null, 6]]}"#
+    );
+    assert_eq!(value_to_csv(&matrix), "1,2.5,-3\n4,NaN,6");
+    assert_eq!(value_to_json(&Value::Scalar(0.5)), r#"{"type": "scalar", "value": 0.5}"#);
+    assert_eq!(value_to_json(&Value::Bool(false)), r#"{"type": "bool", "value": false}"#);
+    assert_eq!(value_to_csv(&Value::Scalar(0.5)), "0.5");
+
+    // Invalid tokens are reported along with the syntax errors, in source order.
+    let source = "fn main() {\n    let a = 1 +;\n    return $a;\n}";
+    let err = Error::Parse(compiler::parser::Parser::new(source).parse_program().expect_err("Lexing should fail"));
+    assert_eq!(
+        error_to_json(&err, source),
+        concat!(
+            r#"{"errors": [{"kind": "parse", "message": "Expected expression, found `;`", "span": {"start": 27, "end": 28, "line": 2, "column": 16}}, "#,
+            r#"{"kind": "parse", "message": "Lexer error: found invalid token `$`", "span": {"start": 40, "end": 41, "line": 3, "column": 12}}]}"#
+        )
+    );
+
+    let source = "fn main() {\n    let a = 1 +;\n    return a;\n}";
+    let mut parser = compiler::parser::Parser::new(source);
+    let err = Error::Parse(parser.parse_program().expect_err("Parsing should fail"));
+    assert_eq!(
+        error_to_json(&err, source),
+        r#"{"errors": [{"kind": "parse", "message": "Expected expression, found `;`", "span": {"start": 27, "end": 28, "line": 2, "column": 16}}]}"#
+    );
+
+    assert_eq!(
+        io_error_to_json("Failed to read file \"missing.ms\""),
+        r#"{"errors": [{"kind": "io", "message": "Failed to read file \"missing.ms\"", "span": null}]}"#
+    );
+
+    // Runtime errors point at the expression that failed.
+    let source = "fn main() {\n    let A = [1, 2; 3, 4];\n    return A[2, 0];\n}";
+    let err = run(source).expect_err("Indexing should fail");
+    assert_eq!(
+        error_to_json(&err, source),
+        r#"{"errors": [{"kind": "runtime", "message": "Index [2, 0] is out of bounds for a 2x2 matrix", "span": {"start": 49, "end": 56, "line": 3, "column": 12}}]}"#
+    );
+}
+
+#[test]
+fn test_engine() {
+    use matrix_script::Arg;
+
+    let engine = Engine::new();
+    let program = engine
+        .compile(
+            "
+    fn predict(X: Matrix, w: Matrix) {
+        return X @ w;
+    }
+
+    fn norm2(v) {
+        return (v' @ v)[0];
+    }
+
+    fn main() {
+        return norm2([3; 4]);
+    }
+    ",
+        )
+        .expect("Failed to compile program");
+
+    let signatures: Vec<String> = program.functions().iter().map(|f| f.to_string()).collect();
+    assert_eq!(
+        signatures,
+        [
+            "fn predict(X: Matrix, w: Matrix) -> Matrix",
+            "fn norm2(v: Matrix) -> f64",
+            "fn main() -> f64",
+        ]
+    );
+    assert!(program.function("missing").is_none());
+
+    assert_eq!(program.run("main").expect("Failed to run main"), Value::Scalar(25.0));
+    let x = [1.0, 2.0, 3.0, 4.0];
+    let w = [1.0, -1.0];
+    let y = program
+        .call("predict", &[Arg::Matrix { rows: 2, cols: 2, data: &x }, Arg::Matrix { rows: 2, cols: 1, data: &w }])
+        .expect("Failed to call predict");
+    assert_eq!(y, Value::Matrix { rows: 2, cols: 1, data: vec![-1.0, -1.0] });
+
+    // Programs compiled by the same engine are independent.
+    let other = engine.compile("fn main() { return true; }").expect("Failed to compile program");
+    assert_eq!(other.run("main").expect("Failed to run main"), Value::Bool(true));
+    assert_eq!(program.run("main").expect("Failed to run main"), Value::Scalar(25.0));
+
+    assert!(matches!(engine.compile("fn main() { return x; }"), Err(Error::Compile(_))));
+}
+
+#[test]
+fn test_host_functions() {
+    use matrix_script::compiler::ast::Type;
+    use matrix_script::compiler::runtime::{alloc_matrix, Matrix};
+    use matrix_script::HostFunction;
+
+    extern "C" fn clamp(x: f64, lo: f64, hi: f64) -> f64 {
+        x.max(lo).min(hi)
+    }
+
+    extern "C" fn choose(flag: bool, a: f64, b: f64) -> f64 {
+        if flag { a } else { b }
+    }
+
+    extern "C" fn row_sums(m: *const Matrix) -> *mut Matrix {
+        let m = unsafe { &*m };
+        let data = unsafe { m.as_slice() };
+        let sums: Vec<f64> = data.chunks(m.cols.max(1) as usize).map(|row| row.iter().sum()).collect();
+        alloc_matrix(m.rows as usize, 1, &sums)
+    }
+
+    let mut engine = Engine::new();
+    unsafe {
+        engine
+            .register_function(HostFunction::new("clamp", vec![Type::Scalar; 3], Type::Scalar, clamp as *const ()))
+            .register_function(HostFunction::new("choose", vec![Type::Bool, Type::Scalar, Type::Scalar], Type::Scalar, choose as *const ()))
+            .register_function(HostFunction::new("row_sums", vec![Type::Matrix], Type::Matrix, row_sums as *const ()));
+    }
+
+    let program = engine
+        .compile(
+            "
+    fn main() {
+        let sums = row_sums([1, 2, 3; 4, 5, 6]);
+        return clamp(sums[1, 0], 0, 10) + choose(1 < 2, 100, 200) + sums[0, 0];
+    }
+
+    fn totals(m: Matrix) {
+        return row_sums(m)';
+    }
+    ",
+        )
+        .expect("Failed to compile program");
+    assert_eq!(program.run("main").expect("Failed to run main"), Value::Scalar(116.0));
+
+    // Return types of host functions feed into inference like any other call.
+    assert_eq!(program.function("totals").unwrap().to_string(), "fn totals(m: Matrix) -> Matrix");
+    let value = program.call("totals", &[(&Value::Matrix { rows: 2, cols: 2, data: vec![1.0, 2.0, 3.0, 4.0] }).into()]);
+    assert_eq!(value.expect("Failed to call totals"), Value::Matrix { rows: 1, cols: 2, data: vec![3.0, 7.0] });
+
+    // Calls are type-checked against the registered signature.
+    let err = engine.compile("fn main() { return clamp([1, 2], 0, 1); }").err().expect("Expected a type error");
+    assert!(err.to_string().contains("Argument 1 of `clamp` must be a scalar, found a matrix"), "{}", err);
+    let err = engine.compile("fn main() { return clamp(1, 2); }").err().expect("Expected an argument count error");
+    assert!(matches!(err, Error::Compile(CompileError::ArgumentCount { expected: 3, found: 2, .. })), "{:?}", err);
+
+    // A script can't redefine a host function.
+    let err = engine.compile("fn clamp(x) { return x; } fn main() { return 0; }").err().expect("Expected a duplicate function error");
+    assert!(matches!(err, Error::Compile(CompileError::DuplicateFunction { ref name, .. }) if name == "clamp"), "{:?}", err);
+}
+
+#[test]
+fn test_extern_functions() {
+    let source = r#"
+    link "libm.so.6";
+    extern fn hypot(x: f64, y: f64) -> f64;
+    extern fn cbrt(x: f64);
+
+    fn main() {
+        return hypot(3, 4) + cbrt(27);
+    }
+    "#;
+
+    let program = compiler::parser::Parser::new(source).parse_program().expect("Failed to parse program");
+    assert_eq!(program.links[0].to_string(), "link \"libm.so.6\";");
+    assert_eq!(program.externs[0].to_string(), "extern fn hypot(x: f64, y: f64) -> f64;");
+    assert_eq!(program.externs[1].to_string(), "extern fn cbrt(x: f64) -> f64;");
+
+    let engine = Engine::new();
+    let compiled = engine.compile(source).expect("Failed to compile program");
+    assert_eq!(compiled.run("main").expect("Failed to run main"), Value::Scalar(8.0));
+
+    // Symbols already loaded into the process resolve without a `link`.
+    let compiled = engine.compile("extern fn sqrt(x: f64) -> f64; fn main() { return sqrt(2) ^ 2; }").expect("Failed to compile program");
+    let Value::Scalar(n) = compiled.run("main").expect("Failed to run main") else { panic!("Expected a scalar") };
+    assert!((n - 2.0).abs() < 1e-12);
+
+    let err = engine.compile("extern fn sqrt(x: f64); fn main() { return sqrt(true); }").err().expect("Expected a type error");
+    assert!(err.to_string().contains("Argument 1 of `sqrt` must be a scalar, found a boolean"), "{}", err);
+
+    let err = engine.compile("link \"libmatrix_script_missing.so\"; fn main() { return 0; }").err().expect("Expected a link error");
+    assert!(matches!(err, Error::Compile(CompileError::LibraryNotFound { ref path, span }) if path == "libmatrix_script_missing.so" && span == Span::new(0, 35)), "{:?}", err);
+
+    let err = engine.compile("extern fn matrix_script_missing(); fn main() { return 0; }").err().expect("Expected a missing symbol error");
+    assert!(matches!(err, Error::Compile(CompileError::UndefinedSymbol { ref name, .. }) if name == "matrix_script_missing"), "{:?}", err);
+
+    let err = engine.compile("extern fn sqrt(x: f64); fn sqrt(x) { return x; } fn main() { return 0; }").err().expect("Expected a duplicate function error");
+    assert!(matches!(err, Error::Compile(CompileError::DuplicateFunction { .. })), "{:?}", err);
+
+    let err = engine.compile("extern fn sqrt(x) -> f64; fn main() { return 0; }").err().expect("Expected a parse error");
+    assert_eq!(err.to_string(), "Parameter `x` of extern function `sqrt` must have a type");
+
+    // `Jit::new` resolves externs too, and reports a missing one instead of letting LLVM abort.
+    let context = Context::create();
+    let compile = |source: &str| {
+        let program = compiler::parser::Parser::new(source).parse_program().expect("Failed to parse program");
+        let mut codegen = compiler::codegen::CodeGen::new(&context, "test_module");
+        codegen.compile_program(&program).expect("Failed to compile program");
+        compiler::jit::Jit::new(codegen.module())
+    };
+    let jit = compile("extern fn sqrt(x: f64) -> f64; fn main() { return sqrt(16); }").expect("Failed to create JIT");
+    assert_eq!(jit.run("main").expect("Failed to run main"), Value::Scalar(4.0));
+    let err = compile("extern fn matrix_script_missing(); fn main() { return matrix_script_missing(); }").err().expect("Expected a missing symbol error");
+    assert_eq!(err, CompileError::UndefinedSymbol { name: "matrix_script_missing".to_string(), span: None });
+    assert_eq!(err.to_string(), "Symbol `matrix_script_missing` could not be resolved");
 }
@@ -1,6 +1,13 @@
+mod common;
+
 #[cfg(test)]
 mod tests {
-    use crate::compiler::{lexer, parser, codegen, jit};
+    use crate::common::{compile_error, run};
+    use matrix_script::compiler::{parser, codegen, jit};
+    use matrix_script::compiler::diagnostic::Span;
+    use matrix_script::compiler::error::{CallError, CompileError, Error, RuntimeError};
+    use matrix_script::compiler::jit::{Arg, Value};
+    use matrix_script::Engine;
     use inkwell::context::Context;
 
     #[test]
@@ -14,45 +21,424 @@ mod tests {
         "#;
 
         let context = Context::create();
-        let mut parser = parser::Parser::new(code).unwrap();
+        let mut parser = parser::Parser::new(code);
         let program = parser.parse_program().unwrap();
 
         let mut codegen = codegen::CodeGen::new(&context, "main");
         codegen.compile_program(&program).unwrap();
 
-        // Verify module creation
-        println!("Generated LLVM IR:");
-        codegen.module().print_to_stderr();
+        let jit = jit::Jit::new(codegen.module()).unwrap();
+        assert_eq!(
+            jit.run("main").unwrap(),
+            Value::Matrix { rows: 2, cols: 2, data: vec![6.0, 8.0, 10.0, 12.0] }
+        );
+    }
 
-        // Run JIT
-        // Note: The return type is a pointer to a struct. The current JIT wrapper `run` expects f64.
-        // We need to bypass the default `run` or use a generic one.
-        // For this test, verifying it compiles and doesn't crash during execution is the goal.
+    #[test]
+    fn test_matrix_multiplication_jit() {
+        let code = r#"
+        fn main() {
+            let A = [[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]];
+            let B = [[7.0, 8.0], [9.0, 10.0], [11.0, 12.0]];
+            return A @ B;
+        }
+        "#;
 
-        // We will try to run it. If the function returns a pointer (u64/u64 size), and we try to read it as f64,
-        // it might give a garbage f64, but it shouldn't crash unless the ABI is wildly different.
-        // On x64, return value is in RAX/XMM0.
-        // Pointers are in RAX. Floats in XMM0.
-        // If the function returns a pointer, it puts it in RAX.
-        // If we expect f64, we read XMM0.
-        // So `func.call()` might return 0.0 or garbage, but checking for "success" means no segfault.
+        assert_eq!(
+            run(code).unwrap(),
+            Value::Matrix { rows: 2, cols: 2, data: vec![58.0, 64.0, 139.0, 154.0] }
+        );
+    }
 
-        let jit = jit::Jit::new(codegen.module()).unwrap();
-        // unsafe {
-        //     let _ = jit.run("main");
-        // }
-        // The `jit.run` signature assumes f64 return.
-        // If we call it, and LLVM generated a function returning a pointer,
-        // and we cast it to `fn() -> f64`, the behavior is undefined but likely just reads XMM0.
-        // To be safe, let's verify if we can check the return type?
-        // No, `jit` wrapper is simple.
-
-        // Let's just run it and ignore the result, assuming it won't crash the process.
-        let result = jit.run("main");
-
-        // We expect `result` to be Ok or Err, but not a crash.
-        // However, if the calling convention mismatch occurs, it *might* behave oddly.
-        // But for MVP this is acceptable verification of "doesn't crash".
-        assert!(result.is_ok());
+    #[test]
+    fn test_matrix_multiplication_inner_dimension_mismatch() {
+        let code = r#"
+        fn main() {
+            let A = [[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]];
+            let B = [[1.0, 2.0], [3.0, 4.0]];
+            return A @ B;
+        }
+        "#;
+
+        let err = run(code).unwrap_err();
+        let start = code.find("A @ B").unwrap();
+        assert_eq!(
+            err,
+            Error::Runtime(RuntimeError::ShapeMismatch {
+                op: "@".to_string(),
+                lhs: (2, 3),
+                rhs: (2, 2),
+                span: Span::new(start, start + "A @ B".len()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_matrix_addition_shape_mismatch() {
+        let code = r#"
+        fn main() {
+            let A = [[1.0, 2.0], [3.0, 4.0]];
+            let B = [1.0, 2.0, 3.0];
+            return A + B;
+        }
+        "#;
+
+        let err = run(code).unwrap_err();
+        assert_eq!(err.to_string(), "Shape mismatch in `+`: left operand is 2x2, right operand is 1x3");
+        assert!(matches!(err, Error::Runtime(RuntimeError::ShapeMismatch { .. })));
+    }
+
+    #[test]
+    fn test_scalar_matrix_broadcasting_jit() {
+        let code = r#"
+        fn main() {
+            let A = [[1.0, 2.0], [3.0, 4.0]];
+            let B = 2.0 * A - 1.0;
+            return 10.0 / (B + A / 4.0);
+        }
+        "#;
+
+        assert_eq!(
+            run(code).unwrap(),
+            Value::Matrix { rows: 2, cols: 2, data: vec![10.0 / 1.25, 10.0 / 3.5, 10.0 / 5.75, 10.0 / 8.0] }
+        );
+    }
+
+    #[test]
+    fn test_scalar_matmul_is_rejected() {
+        let code = r#"
+        fn main() {
+            let A = [[1.0, 2.0], [3.0, 4.0]];
+            return 2.0 @ A;
+        }
+        "#;
+
+        let err = compile_error(code);
+        assert_eq!(err.to_string(), "Operator @ requires matrix operands");
+        assert!(matches!(err, CompileError::TypeError { .. }));
+    }
+
+    #[test]
+    fn test_elementwise_operators_jit() {
+        let code = r#"
+        fn main() {
+            let A = [[1.0, 2.0], [3.0, 4.0]];
+            let B = [[5.0, 6.0], [7.0, 8.0]];
+            return A .* B - A ./ B;
+        }
+        "#;
+
+        assert_eq!(
+            run(code).unwrap(),
+            Value::Matrix { rows: 2, cols: 2, data: vec![5.0 - 1.0 / 5.0, 12.0 - 2.0 / 6.0, 21.0 - 3.0 / 7.0, 32.0 - 4.0 / 8.0] }
+        );
+    }
+
+    #[test]
+    fn test_elementwise_shape_mismatch() {
+        let code = r#"
+        fn main() {
+            let A = [[1.0, 2.0], [3.0, 4.0]];
+            let B = [[1.0, 2.0, 3.0]];
+            return A .* B;
+        }
+        "#;
+
+        let err = run(code).unwrap_err();
+        assert_eq!(err.to_string(), "Shape mismatch in `.*`: left operand is 2x2, right operand is 1x3");
+    }
+
+    #[test]
+    fn test_transpose_shape() {
+        // A' is 3x2, so adding the 2x3 original must report both shapes.
+        let code = r#"
+        fn main() {
+            let A = [[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]];
+            let G = transpose(A) @ A;
+            return A' + A;
+        }
+        "#;
+
+        let program = parser::Parser::new(code).parse_program().unwrap();
+        assert_eq!(program.functions[0].body[2].to_string(), "return (A' + A);");
+
+        let err = run(code).unwrap_err();
+        assert_eq!(err.to_string(), "Shape mismatch in `+`: left operand is 3x2, right operand is 2x3");
+
+        // `transpose` is a builtin, so a script can't define or declare its own.
+        let code = "fn transpose(A) { return A; } extern fn transpose(x: f64) -> f64; fn main() { return 0; }";
+        let err = parser::Parser::new(code).parse_program().unwrap_err();
+        assert_eq!(
+            err.errors,
+            vec![
+                CompileError::BuiltinFunction { name: "transpose".to_string(), span: Span::new(3, 12) },
+                CompileError::BuiltinFunction { name: "transpose".to_string(), span: Span::new(40, 49) },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_matrix_indexing_jit() {
+        let code = r#"
+        fn main() {
+            let A = [[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]];
+            let C = A @ A';
+            let v = [7.0, 8.0, 9.0];
+            return A[1, 2] + C[0, 1] * 10.0 + v[2] * 100.0;
+        }
+        "#;
+
+        assert_eq!(run(code).unwrap(), Value::Scalar(6.0 + 320.0 + 900.0));
+    }
+
+    #[test]
+    fn test_matrix_index_out_of_bounds() {
+        let prefix = "fn main() { let A = [[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]; return ";
+        // Spans are relative to the index expression.
+        let at = |start, end| Span::new(prefix.len() + start, prefix.len() + end);
+        let cases = [
+            ("A[2.0, 0.0]", RuntimeError::IndexOutOfBounds { index: (2, 0), shape: (2, 3), span: at(0, 11) }),
+            ("A[0.0 - 1.0, 1.0]", RuntimeError::IndexOutOfBounds { index: (-1, 1), shape: (2, 3), span: at(0, 17) }),
+            ("A[6.0]", RuntimeError::LinearIndexOutOfBounds { index: 6, shape: (2, 3), span: at(0, 6) }),
+            // Indices that are not whole numbers are rejected before they are converted.
+            ("A[0.7]", RuntimeError::InvalidIndex { index: 0.7, span: at(2, 5) }),
+            ("A[1.0, 0.0 - 0.5]", RuntimeError::InvalidIndex { index: -0.5, span: at(7, 16) }),
+            ("A[inf, 0.0]", RuntimeError::InvalidIndex { index: f64::INFINITY, span: at(2, 5) }),
+            ("A[1e300]", RuntimeError::InvalidIndex { index: 1e300, span: at(2, 7) }),
+            ("A[0.0:1.5, :]", RuntimeError::InvalidIndex { index: 1.5, span: at(6, 9) }),
+            ("A[0.0, ::inf]", RuntimeError::InvalidIndex { index: f64::INFINITY, span: at(9, 12) }),
+        ];
+
+        for (index_expr, expected) in cases {
+            let code = format!("{}{}; }}", prefix, index_expr);
+            assert_eq!(run(&code).unwrap_err(), Error::Runtime(expected));
+        }
+
+        // NaN never compares equal, so match on it instead.
+        let code = "fn main() { let A = [1.0, 2.0]; return A[nan]; }";
+        let err = run(code).unwrap_err();
+        assert!(matches!(err, Error::Runtime(RuntimeError::InvalidIndex { index, .. }) if index.is_nan()), "{:?}", err);
+    }
+
+    #[test]
+    fn test_matrix_slicing_jit() {
+        let code = r#"
+        fn main() {
+            let A = [[1.0, 2.0, 3.0, 4.0], [5.0, 6.0, 7.0, 8.0], [9.0, 10.0, 11.0, 12.0]];
+            let B = A[:, 1:];
+            let C = A[0:3:2, ::2];
+            let r = A[1, 1:3];
+            return B[2, 2] + C[1, 1] * 10.0 + r[1] * 100.0;
+        }
+        "#;
+
+        let program = parser::Parser::new(code).parse_program().unwrap();
+        assert_eq!(program.functions[0].body[1].to_string(), "let B = A[:, 1:];");
+        assert_eq!(program.functions[0].body[2].to_string(), "let C = A[0:3:2, ::2];");
+
+        assert_eq!(run(code).unwrap(), Value::Scalar(12.0 + 110.0 + 700.0));
+
+        // A step far past the end takes just the first element, even where `end - start + step`
+        // would overflow (2^63 - 1024 is the largest step a float literal can hold), and
+        // empty ranges stay empty.
+        let code = "
+        fn main() {
+            let A = [1.0];
+            for k in 0..11 { A = [A, A + 1.0]; }
+            return [A[0, 2:2048:9223372036854774784], A[0, 5:5:9223372036854774784]];
+        }
+        ";
+        assert_eq!(run(code).unwrap(), Value::Matrix { rows: 1, cols: 1, data: vec![2.0] });
+    }
+
+    #[test]
+    fn test_matrix_slice_out_of_bounds() {
+        let code = r#"
+        fn main() {
+            let A = [[1.0, 2.0], [3.0, 4.0]];
+            let B = A[0:3, :];
+            return B[0, 0];
+        }
+        "#;
+
+        let err = run(code).unwrap_err();
+        let start = code.find("A[0:3, :]").unwrap();
+        assert_eq!(
+            err,
+            Error::Runtime(RuntimeError::InvalidSlice { start: 0, end: 3, step: 1, len: 2, span: Span::new(start, start + 9) })
+        );
+    }
+
+    #[test]
+    fn test_matrix_power_jit() {
+        let code = r#"
+        fn main() {
+            let P = [[0.5, 0.5], [0.25, 0.75]];
+            let state = [[1.0, 0.0]] @ P ^ 3;
+            let F = [[1.0, 1.0], [1.0, 0.0]] ^ 10;
+            let S = ([[1.0, 2.0], [3.0, 4.0]] .^ 2) .^ 0.5;
+            return state[0, 0] * 64.0 + F[0, 1] * 10.0 + S[1, 0] * 1000.0 + (P ^ 0)[0, 1];
+        }
+        "#;
+
+        // state = [22/64, 42/64], F[0, 1] is the 10th Fibonacci number.
+        assert_eq!(run(code).unwrap(), Value::Scalar(22.0 + 550.0 + 3000.0));
+    }
+
+    #[test]
+    fn test_matrix_power_errors() {
+        let prefix = "fn main() { let A = [[1.0, 2.0], [3.0, 4.0]]; return ";
+        let at = |start, end| Span::new(prefix.len() + start, prefix.len() + end);
+        let cases = [
+            ("[[1.0, 2.0, 3.0]] ^ 2", RuntimeError::NonSquareMatrix { op: "^".to_string(), shape: (1, 3), span: at(0, 21) }),
+            ("A ^ 0.5", RuntimeError::InvalidExponent { op: "^".to_string(), exponent: 0.5, span: at(0, 7) }),
+            ("A ^ (0 - 1)", RuntimeError::InvalidExponent { op: "^".to_string(), exponent: -1.0, span: at(0, 11) }),
+        ];
+
+        for (power_expr, expected) in cases {
+            let code = format!("{}{}; }}", prefix, power_expr);
+            assert_eq!(run(&code).unwrap_err(), Error::Runtime(expected));
+        }
+
+        let err = compile_error("fn main() { return 2.0 ^ [[1.0, 2.0]]; }");
+        assert!(matches!(err, CompileError::TypeError { .. }));
+    }
+
+    #[test]
+    fn test_block_matrix_jit() {
+        let code = r#"
+        fn main() {
+            let H = [2.0, 0.0; 0.0, 4.0];
+            let a = [1.0; 1.0];
+            let K = [H, a; a', 0.0];
+            let M = [[H, H], [1.0, 2.0, 3.0, 4.0]];
+            return K[2, 0] + K[1, 2] * 10.0 + K[2, 2] * 100.0 + M[0, 2] * 1000.0 + M[2, 3] * 10000.0;
+        }
+        "#;
+
+        let program = parser::Parser::new(code).parse_program().unwrap();
+        assert_eq!(program.functions[0].body[0].to_string(), "let H = [[2, 0], [0, 4]];");
+        assert_eq!(program.functions[0].body[2].to_string(), "let K = [[H, a], [a', 0]];");
+        assert_eq!(program.functions[0].body[3].to_string(), "let M = [[H, H], [1, 2, 3, 4]];");
+
+        assert_eq!(run(code).unwrap(), Value::Scalar(1.0 + 10.0 + 0.0 + 2000.0 + 40000.0));
+    }
+
+    #[test]
+    fn test_nested_literals_are_rows_only_when_written_out() {
+        let code = r#"
+        fn nested() { return [[1.0, 2.0], [3.0, 4.0]]; }
+        fn side_by_side() { let a = [1.0, 2.0]; let b = [3.0, 4.0]; return [a, b]; }
+        fn stacked() { let a = [1.0, 2.0]; let b = [3.0, 4.0]; return [a; b]; }
+        "#;
+
+        let engine = Engine::new();
+        let program = engine.compile(code).unwrap();
+        let square = Value::Matrix { rows: 2, cols: 2, data: vec![1.0, 2.0, 3.0, 4.0] };
+        assert_eq!(program.run("nested").unwrap(), square);
+        assert_eq!(program.run("side_by_side").unwrap(), Value::Matrix { rows: 1, cols: 4, data: vec![1.0, 2.0, 3.0, 4.0] });
+        assert_eq!(program.run("stacked").unwrap(), square);
+
+        // Each inner literal is a row, so single-element rows make a column.
+        assert_eq!(run("fn main() { return [[1.0], [2.0]]; }").unwrap(), Value::Matrix { rows: 2, cols: 1, data: vec![1.0, 2.0] });
+
+        // Mixing the two forms is ambiguous, so it is rejected.
+        let code = "fn main() { let b = [3.0, 4.0]; return [[1.0, 2.0], b]; }";
+        let err = compile_error(code);
+        let start = code.find("[[").unwrap();
+        assert!(matches!(err, CompileError::ShapeError { span, .. } if span == Span::new(start, code.len() - 3)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_block_matrix_shape_mismatch() {
+        let prefix = "fn main() { let A = [1.0, 2.0; 3.0, 4.0]; return ";
+        let at = |start, end| Span::new(prefix.len() + start, prefix.len() + end);
+        let cases = [
+            ("[A, [1.0; 2.0; 3.0]]", RuntimeError::ShapeMismatch { op: ",".to_string(), lhs: (2, 2), rhs: (3, 1), span: at(0, 20) }),
+            ("[A; 1.0, 2.0, 3.0]", RuntimeError::ShapeMismatch { op: ";".to_string(), lhs: (2, 2), rhs: (1, 3), span: at(0, 18) }),
+        ];
+
+        for (literal, expected) in cases {
+            let code = format!("{}{}; }}", prefix, literal);
+            assert_eq!(run(&code).unwrap_err(), Error::Runtime(expected));
+        }
+    }
+
+    #[test]
+    fn test_call_with_host_arguments() {
+        let code = r#"
+        fn predict(X: Matrix, w: Matrix) {
+            return X @ w;
+        }
+
+        fn scale(A: Matrix, s: f64, negate: bool) {
+            if negate {
+                A[0, 0] = 0 - A[0, 0];
+            }
+            return A * s;
+        }
+
+        fn same(A: Matrix) {
+            return A;
+        }
+        "#;
+
+        let engine = Engine::new();
+        let program = engine.compile(code).unwrap();
+
+        // Compile once, call many times.
+        let x = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        for (w, expected) in [([1.0, 0.0], [1.0, 3.0, 5.0]), ([0.5, 0.5], [1.5, 3.5, 5.5])] {
+            let args = [Arg::Matrix { rows: 3, cols: 2, data: &x }, Arg::Matrix { rows: 2, cols: 1, data: &w }];
+            assert_eq!(program.call("predict", &args).unwrap(), Value::Matrix { rows: 3, cols: 1, data: expected.to_vec() });
+        }
+
+        // The function works on a copy of the caller's data.
+        let a = [1.0, 2.0];
+        let args = [Arg::Matrix { rows: 1, cols: 2, data: &a }, Arg::from(10.0), Arg::from(true)];
+        assert_eq!(program.call("scale", &args).unwrap(), Value::Matrix { rows: 1, cols: 2, data: vec![-10.0, 20.0] });
+        assert_eq!(a, [1.0, 2.0]);
+
+        // Results can be passed back in, including an argument returned as is.
+        let value = program.call("same", &[Arg::Matrix { rows: 1, cols: 2, data: &a }]).unwrap();
+        assert_eq!(program.call("same", &[Arg::from(&value)]).unwrap(), value);
+
+        // A runtime error in the callee is reported like for `run`.
+        let err = program.call("predict", &[Arg::Matrix { rows: 1, cols: 2, data: &a }, Arg::Matrix { rows: 1, cols: 2, data: &a }]).unwrap_err();
+        assert!(matches!(err, Error::Runtime(RuntimeError::ShapeMismatch { .. })));
+    }
+
+    #[test]
+    fn test_call_arguments_are_validated() {
+        let code = "fn scale(A: Matrix, s: f64) { return A * s; }";
+
+        let engine = Engine::new();
+        let program = engine.compile(code).unwrap();
+        let a = [1.0, 2.0, 3.0];
+
+        let err = program.call("missing", &[]).unwrap_err();
+        assert_eq!(err, Error::Call(CallError::UndefinedFunction { name: "missing".to_string() }));
+
+        let err = program.run("scale").unwrap_err();
+        assert_eq!(err.to_string(), "Function `scale` takes 2 arguments, found 0");
+
+        let err = program.call("scale", &[Arg::from(2.0), Arg::from(2.0)]).unwrap_err();
+        assert_eq!(err.to_string(), "Argument 1 of `scale` must be a matrix, found a scalar");
+
+        let err = program.call("scale", &[Arg::Matrix { rows: 2, cols: 2, data: &a }, Arg::from(2.0)]).unwrap_err();
+        assert_eq!(
+            err,
+            Error::Call(CallError::MatrixData { name: "scale".to_string(), index: 1, rows: 2, cols: 2, len: 3 })
+        );
+    }
+
+    #[test]
+    fn test_unknown_runtime_error_kind() {
+        use matrix_script::compiler::runtime;
+
+        runtime::take_error();
+        unsafe { runtime::matrix_script_raise(99, std::ptr::null(), 0, 0, 0, 0, 0, 0) };
+        assert!(runtime::matrix_script_error_pending());
+        assert_eq!(runtime::take_error(), Some(RuntimeError::Unknown { kind: 99, span: Span::default() }));
     }
 }